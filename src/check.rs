@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::Serialize;
+use structopt::StructOpt;
+
+use lib::{
+    config::{Brightness, Config, DeviceConfig, Format, Opts as ConfigOpts, DEFAULT_PROFILE},
+    display::{BrightnessCurve, Luminance},
+    location::Location,
+    logging::*,
+    prelude::*,
+    types::*,
+};
+
+#[derive(StructOpt, Debug)]
+pub struct Opts {
+    #[structopt(flatten)]
+    pub logging: LogOpts,
+
+    /// only check the config, without showing which displays each rule claims.
+    #[structopt(long)]
+    pub offline: bool,
+
+    /// config file format: yaml, toml, or json. Detected from the extension if unset.
+    #[structopt(long)]
+    pub format: Option<Format>,
+
+    /// config file. If absent, the daemon's default config is checked.
+    pub config: Option<PathBuf>,
+}
+
+/// The effective config, as the daemon would use it.
+#[derive(Serialize)]
+struct Effective<'a> {
+    geo: &'a Location,
+    /// the profile the daemon starts with.
+    profile: &'a str,
+    /// in priority order.
+    devices: Vec<Rule<'a>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    profiles: BTreeMap<&'a str, Vec<Rule<'a>>>,
+}
+
+#[derive(Serialize)]
+struct Rule<'a> {
+    #[serde(flatten)]
+    matcher: &'a DeviceMatcher,
+    day_brightness: Brightness,
+    night_brightness: Brightness,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    curve: Option<&'a BrightnessCurve>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    luminance: Option<&'a Luminance>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    fallback: bool,
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    init_logger(&opts.logging);
+
+    let path = match opts.config {
+        Some(path) => path,
+        None => ConfigOpts::find()?,
+    };
+    info!("checking {}", path.display());
+
+    // problems are prefixed with the file, line, and column they're at
+    let cfg = Config::new(ConfigOpts::from_file(&path, opts.format)?)?;
+
+    for warning in cfg.warnings.iter() {
+        warn!("{}: {}", path.display(), warning);
+    }
+
+    let effective = Effective {
+        geo: &cfg.geo,
+        profile: &cfg.profile,
+        devices: rules(&cfg.devices),
+        profiles: cfg
+            .profiles
+            .iter()
+            .map(|(name, devices)| (name.as_str(), rules(devices)))
+            .collect(),
+    };
+    println!("{}", serde_yaml::to_string(&effective)?);
+
+    for profile in cfg.profile_names() {
+        let devices = cfg.rules(profile).unwrap_or_default();
+        for (i, rule) in devices.iter().enumerate() {
+            if let Some(j) = devices[..i]
+                .iter()
+                .position(|earlier| earlier.matcher.covers(&rule.matcher))
+            {
+                warn!(
+                    "{}rule {} is unreachable: rule {} claims every display it {}",
+                    profile_prefix(profile),
+                    i + 1,
+                    j + 1,
+                    rule.matcher
+                );
+            }
+        }
+    }
+
+    if opts.offline {
+        return Ok(());
+    }
+    show_claims(&cfg);
+    Ok(())
+}
+
+fn rules(devices: &[DeviceConfig]) -> Vec<Rule<'_>> {
+    devices
+        .iter()
+        .map(|d| Rule {
+            matcher: &d.matcher,
+            day_brightness: d.day_brightness,
+            night_brightness: d.night_brightness,
+            curve: Some(&d.curve).filter(|c| **c != BrightnessCurve::default()),
+            luminance: d.luminance.as_ref(),
+            fallback: d.fallback,
+        })
+        .collect()
+}
+
+/// Names the profile in messages about its rules, unless it's the default.
+fn profile_prefix(profile: &str) -> String {
+    if profile == DEFAULT_PROFILE {
+        String::new()
+    } else {
+        format!("profile {}: ", profile)
+    }
+}
+
+/// Logs which rule of each profile claims each discovered display.
+fn show_claims(cfg: &Config) {
+    let mut disps = match Displays::new(&cfg.devices, &cfg.discovery) {
+        Ok(disps) => disps,
+        Err(e) => {
+            warn!("can't show which displays rules claim: {}", e);
+            return;
+        }
+    };
+
+    for profile in cfg.profile_names() {
+        let devices = cfg.rules(profile).unwrap_or_default();
+        disps.assign(devices);
+
+        let mut claimed = vec![false; devices.len()];
+        for disp in disps.iter() {
+            if let Some(i) = devices.iter().position(|d| std::ptr::eq(d, disp.config())) {
+                claimed[i] = true;
+                info!(
+                    "{}rule {} claims {} ({})",
+                    profile_prefix(profile),
+                    i + 1,
+                    disp,
+                    disp.identity()
+                );
+            }
+        }
+        for (i, _) in claimed.iter().enumerate().filter(|(_, c)| !**c) {
+            info!(
+                "{}rule {} claims no displays",
+                profile_prefix(profile),
+                i + 1
+            );
+        }
+    }
+}
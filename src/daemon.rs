@@ -1,28 +1,83 @@
 use std::convert::TryInto;
+use std::future::pending;
 
 use adaptive_backoff::prelude::*;
 use anyhow::{format_err, Error, Result};
 use chrono::{DateTime, Duration, Local, Utc};
-use futures::future::try_join_all;
+use futures::future::{join_all, try_join_all};
 use humantime::format_duration;
 use log::*;
 use structopt::StructOpt;
-use tokio::{select, time::sleep};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+    time::{interval_at, sleep, timeout, Instant, Interval, MissedTickBehavior},
+};
 
 use lib::{
     alarm::Alarm,
-    config::{Config, GeoOpts},
-    display::{BrightnessOps, Display, Displays},
+    config::{Config, ManualChangePolicy},
+    control::{self, Request},
+    display::{BrightnessHardware, Display, Displays, WriteOutcome},
+    hooks::{Event, Hooks},
+    location::{GpsdSource, Location},
+    state::{BrightnessOverride, Phase, State},
 };
 
 #[derive(StructOpt, Debug)]
 pub struct Opts {
-    pub config: std::path::PathBuf,
+    /// config file format: yaml, toml, or json. Detected from the extension if unset.
+    #[structopt(long)]
+    pub format: Option<lib::config::Format>,
+
+    /// profile to start with, instead of the one last switched to or configured.
+    #[structopt(long)]
+    pub profile: Option<String>,
+
+    /// config file. If absent, $DLUX_CONFIG, $XDG_CONFIG_HOME/dlux/config.yaml,
+    /// and /etc/dlux/config.yaml are tried in order.
+    pub config: Option<std::path::PathBuf>,
 }
 
-pub async fn run(cfg: lib::config::Config) -> Result<(), Error> {
-    let mut disps = Displays::new(&cfg.devices)?;
+pub async fn run(cfg: lib::config::Config, profile: Option<String>) -> Result<(), Error> {
+    for warning in cfg.warnings.iter() {
+        warn!("{}", warning);
+    }
+
+    let state_path = cfg
+        .state_file
+        .clone()
+        .or_else(State::default_path)
+        .ok_or_else(|| {
+            format_err!("no state file was given and neither $STATE_DIRECTORY nor $HOME are set")
+        })?;
+    debug!("keeping state in {:?}", state_path);
+    let mut state = State::load_or_default(&state_path);
+
+    // A profile switched to at runtime outlives restarts, unless it's since been
+    // removed from the config.
+    let mut active = match profile {
+        Some(p) if cfg.rules(&p).is_none() => {
+            return Err(format_err!(
+                "profile {:?} is not defined; profiles are: {}",
+                p,
+                cfg.profile_names().collect::<Vec<_>>().join(", ")
+            ))
+        }
+        Some(p) => p,
+        None => state
+            .profile
+            .clone()
+            .filter(|p| cfg.rules(p).is_some())
+            .unwrap_or_else(|| cfg.profile.clone()),
+    };
+    info!("using profile {}", active);
+
+    let hooks = Hooks::new(cfg.hooks.clone(), cfg.max_concurrent_hooks);
+    let mut disps = Displays::new(cfg.rules(&active).unwrap_or_default(), &cfg.discovery)?;
     let mut alarm = Alarm::new()?;
+    info!("following the sun at {}", cfg.geo);
     info!("discovered {} monitors", disps.len());
 
     if disps.is_empty() {
@@ -31,10 +86,65 @@ pub async fn run(cfg: lib::config::Config) -> Result<(), Error> {
         ));
     }
 
-    loop {
-        update_monitors_from_time(&mut disps, &cfg).await;
+    for d in disps.iter() {
+        hooks.fire(Event::DisplayAdded {
+            display: d.identity().to_owned(),
+            bus: d.bus().to_owned(),
+        });
+    }
 
-        let next_dt = get_next_event::<Local>(&cfg.geo, Local::now());
+    if cfg.restore_brightness_on_exit {
+        record_original_brightness(&disps, &mut state).await;
+    }
+
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut poll = cfg.manual_changes.as_ref().map(|o| {
+        let period = std::time::Duration::from_secs(o.poll_interval_secs.max(1));
+        let mut poll = interval_at(Instant::now() + period, period);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        poll
+    });
+    let socket_path = cfg
+        .control_socket
+        .clone()
+        .unwrap_or_else(control::default_socket_path);
+    let server = match control::Server::bind(&socket_path).await {
+        Ok(s) => {
+            debug!("accepting commands on {:?}", s.path());
+            Some(s)
+        }
+        Err(e) => {
+            warn!("profiles can't be switched at runtime: {:#}", e);
+            None
+        }
+    };
+
+    let mut geo = cfg.geo;
+    let mut moves = cfg
+        .gpsd
+        .clone()
+        .map(|opts| GpsdSource::new(opts, cfg.geo).spawn());
+
+    let mut resumed = true;
+    'events: loop {
+        let phase = update_monitors_from_time(&disps, &geo, &mut state, &hooks, resumed).await;
+        resumed = false;
+        // The phase is kept across restarts, so starting up isn't mistaken for sunrise
+        // or sunset, while one missed while stopped still runs the hooks.
+        if state.phase != Some(phase) {
+            if state.phase.is_some() {
+                hooks.fire(if phase.is_daytime() {
+                    Event::Sunrise
+                } else {
+                    Event::Sunset
+                });
+            }
+            state.phase = Some(phase);
+        }
+        save_state(&state);
+
+        let next_dt = get_next_event::<Local>(&geo, Local::now());
         alarm.reset(next_dt)?;
         info!(
             "sleeping for {} until {}",
@@ -45,14 +155,308 @@ pub async fn run(cfg: lib::config::Config) -> Result<(), Error> {
             next_dt.with_timezone(&Local)
         );
 
-        alarm.future()?.await?;
+        loop {
+            let wake = alarm.future()?;
+            select! {
+                res = wake => {
+                    res?;
+                    break;
+                },
+                _ = interrupt.recv() => break 'events,
+                _ = terminate.recv() => break 'events,
+                _ = tick(poll.as_mut()) => {
+                    if let Some(opts) = &cfg.manual_changes {
+                        check_manual_changes(&disps, &geo, opts.policy, &mut state, &hooks)
+                            .await;
+                    }
+                },
+                moved_to = moved(moves.as_mut()) => {
+                    info!("following the sun at {} instead", moved_to);
+                    geo = moved_to;
+                    break;
+                },
+                res = accept(server.as_ref()) => {
+                    let (req, conn) = match res {
+                        Ok(r) => r,
+                        Err(e) => {
+                            warn!("{:#}", e);
+                            continue;
+                        }
+                    };
+                    let (reply, switched) = match req {
+                        Request::Profiles => (Ok(list_profiles(&cfg, &active)), false),
+                        Request::SetProfile(name) => match cfg.rules(&name) {
+                            Some(rules) => {
+                                info!("switching from profile {} to {}", active, name);
+                                let before = claimed(&disps);
+                                disps.assign(rules);
+                                let after = claimed(&disps);
+                                for (display, bus) in before.iter().filter(|c| !after.contains(c)) {
+                                    hooks.fire(Event::DisplayRemoved {
+                                        display: display.clone(),
+                                        bus: bus.clone(),
+                                    });
+                                }
+                                for (display, bus) in after.iter().filter(|c| !before.contains(c)) {
+                                    hooks.fire(Event::DisplayAdded {
+                                        display: display.clone(),
+                                        bus: bus.clone(),
+                                    });
+                                }
+
+                                // Overrides were made against the old profile's brightness.
+                                for (identity, s) in state.displays.iter_mut() {
+                                    let ended = s.brightness_override.take().is_some();
+                                    match before.iter().find(|(i, _)| i == identity) {
+                                        Some((display, bus)) if ended => {
+                                            hooks.fire(Event::OverrideEnd {
+                                                display: display.clone(),
+                                                bus: bus.clone(),
+                                            })
+                                        }
+                                        _ => (),
+                                    }
+                                }
+                                state.profile = Some(name.clone());
+                                active = name;
+                                (Ok(format!("switched to profile {}\n", active)), true)
+                            }
+                            None => (
+                                Err(format_err!(
+                                    "profile {:?} is not defined; profiles are: {}",
+                                    name,
+                                    cfg.profile_names().collect::<Vec<_>>().join(", ")
+                                )),
+                                false,
+                            ),
+                        },
+                    };
+                    if let Err(e) = conn.reply(reply).await {
+                        debug!("failed to reply to control request: {}", e);
+                    }
+                    if switched {
+                        break;
+                    }
+                },
+            }
+        }
         debug!("awake, time is now: {}", Local::now());
     }
+
+    info!("stopping");
+    if cfg.restore_brightness_on_exit {
+        restore_original_brightness(&disps, &mut state).await;
+        save_state(&state);
+    }
+    Ok(())
+}
+
+/// Waits for the next tick of the interval, or forever without one.
+async fn tick(interval: Option<&mut Interval>) {
+    match interval {
+        Some(i) => {
+            i.tick().await;
+        }
+        None => pending().await,
+    }
+}
+
+/// Waits for the location to change, or forever without a source of locations.
+async fn moved(moves: Option<&mut watch::Receiver<Location>>) -> Location {
+    if let Some(rx) = moves {
+        if rx.changed().await.is_ok() {
+            return *rx.borrow_and_update();
+        }
+    }
+    pending().await
+}
+
+/// The identity and bus of each display which is claimed by a rule.
+fn claimed(disps: &Displays<'_>) -> Vec<(String, String)> {
+    disps
+        .iter()
+        .map(|d| (d.identity().to_owned(), d.bus().to_owned()))
+        .collect()
+}
+
+/// Waits for the next control request, or forever without a control socket.
+async fn accept(server: Option<&control::Server>) -> Result<(Request, control::Connection)> {
+    match server {
+        Some(s) => s.accept().await,
+        None => pending().await,
+    }
+}
+
+/// Lists profiles one per line, marking the active one.
+fn list_profiles(cfg: &Config, active: &str) -> String {
+    cfg.profile_names()
+        .map(|p| {
+            let mark = if p == active { "*" } else { " " };
+            format!("{} {}\n", mark, p)
+        })
+        .collect()
+}
+
+/// Brightness changes smaller than this, in percent, are rounding rather than
+/// someone using the display's buttons.
+const MANUAL_CHANGE_THRESHOLD: f64 = 2.0;
+
+/// How long setting a display back to its target is retried for, with the `enforce`
+/// policy.
+const ENFORCE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Checks displays for brightness changed outside of dlux since it last set them, and
+/// handles the changes according to the policy.
+async fn check_manual_changes<'a>(
+    disps: &Displays<'a>,
+    geo: &Location,
+    policy: ManualChangePolicy,
+    state: &mut State,
+    hooks: &Hooks,
+) {
+    let (phase, started) = current_phase(geo);
+    let reads = disps
+        .iter()
+        .filter_map(|d| Some((d, state.get(d.identity())?.observed?)))
+        .map(|(d, expected)| async move {
+            let read = d
+                .with_device(move |dev| {
+                    Ok((dev.brightness()?, dev.settled_brightness(expected / 100.0)?))
+                })
+                .await;
+            (d, expected, read)
+        });
+
+    let mut changed = false;
+    for (disp, expected, res) in join_all(reads).await {
+        // Compared to what the display reads back at the expected brightness, as
+        // curves may not land on it exactly.
+        let (actual, settled) = match res {
+            Ok((b, settled)) => (b * 100.0, settled * 100.0),
+            Err(e) => {
+                debug!("failed to check brightness of {}: {}", disp, e);
+                continue;
+            }
+        };
+        if (actual - settled).abs() < MANUAL_CHANGE_THRESHOLD {
+            continue;
+        }
+
+        info!(
+            "brightness of {} was changed from {:.0}% to {:.0}% outside of dlux",
+            disp, expected, actual
+        );
+        changed = true;
+        let configured = disp.target_brightness(phase.is_daytime()) * 100.0;
+        let s = state.display(disp.identity());
+        match policy {
+            ManualChangePolicy::Keep => {
+                info!("keeping {:.0}% for {} until {} ends", actual, disp, phase);
+                s.brightness_override = Some(BrightnessOverride {
+                    brightness: actual,
+                    phase,
+                    since: Utc::now(),
+                });
+                hooks.fire(Event::OverrideStart {
+                    display: disp.identity().to_owned(),
+                    bus: disp.bus().to_owned(),
+                    brightness: actual,
+                });
+            }
+            ManualChangePolicy::Shift => {
+                info!(
+                    "shifting day and night brightness of {} by {:+.0}%",
+                    disp,
+                    actual - configured
+                );
+                s.brightness_override = None;
+                s.offset = Some(actual - configured);
+            }
+            ManualChangePolicy::Enforce => {
+                let target = s.target_for(configured, phase, started);
+                info!("setting {} back to {:.0}%", disp, target);
+                // Not retried for long, as other events wait for it.
+                let write = retry_monitor(disp, target / 100.0, hooks);
+                match timeout(ENFORCE_TIMEOUT, write).await {
+                    Ok(Ok(WriteOutcome::Skipped)) | Ok(Ok(WriteOutcome::Verified)) => {
+                        s.observed = Some(target)
+                    }
+                    Ok(_) => s.observed = None,
+                    // Still expected at the target, so it's checked again.
+                    Err(_) => warn!(
+                        "gave up setting {} back after {:?}, trying again at the next check",
+                        disp, ENFORCE_TIMEOUT
+                    ),
+                }
+                continue;
+            }
+        }
+        s.target = Some(actual);
+        s.observed = Some(actual);
+    }
+
+    if changed {
+        save_state(state);
+    }
+}
+
+fn save_state(state: &State) {
+    if let Err(e) = state.save() {
+        warn!("failed to save daemon state: {:#}", e);
+    }
+}
+
+/// Records the brightness of displays which dlux hasn't changed yet. Displays already
+/// recorded keep their value, as an unclean exit leaves them at dlux's brightness.
+async fn record_original_brightness<'a>(disps: &Displays<'a>, state: &mut State) {
+    let reads = disps
+        .iter()
+        .filter(|d| state.get(d.identity()).and_then(|s| s.original).is_none())
+        .map(|d| async move { (d, d.with_device(|dev| dev.brightness()).await) });
+
+    for (disp, res) in join_all(reads).await {
+        match res {
+            Ok(b) => {
+                debug!("{} started at {:.0}% brightness", disp, b * 100.0);
+                state.display(disp.identity()).original = Some(b * 100.0);
+            }
+            Err(e) => warn!(
+                "failed to read brightness of {}, it won't be restored on exit: {}",
+                disp, e
+            ),
+        }
+    }
+}
+
+async fn restore_original_brightness<'a>(disps: &Displays<'a>, state: &mut State) {
+    let writes = disps
+        .iter()
+        .filter_map(|d| Some((d, state.get(d.identity())?.original?)))
+        .map(|(d, b)| async move {
+            (
+                d,
+                b,
+                d.with_device(move |dev| dev.set_brightness(b / 100.0))
+                    .await,
+            )
+        });
+
+    for (disp, b, res) in join_all(writes).await {
+        match res {
+            Ok(outcome) => {
+                info!("restored {} to {:.0}%: {}", disp, b, outcome);
+                let s = state.display(disp.identity());
+                s.original = None;
+                s.observed = Some(b);
+            }
+            Err(e) => warn!("failed to restore brightness of {}: {}", disp, e),
+        }
+    }
 }
 
 // A bit delicate: we need to check in local timezone so our dates are correct.
 // Tomorrow in UTC != tomorrow Local.
-fn get_next_event<T: chrono::TimeZone>(opts: &GeoOpts, now: chrono::DateTime<T>) -> DateTime<Utc> {
+fn get_next_event<T: chrono::TimeZone>(opts: &Location, now: chrono::DateTime<T>) -> DateTime<Utc> {
     let today = now.with_timezone(&Local);
     let geo = get_start_stop_at_date(opts, today.date_naive());
 
@@ -68,7 +472,7 @@ fn get_next_event<T: chrono::TimeZone>(opts: &GeoOpts, now: chrono::DateTime<T>)
 }
 
 fn get_start_stop_at_date(
-    geo: &GeoOpts,
+    geo: &Location,
     date: chrono::NaiveDate,
 ) -> (DateTime<Utc>, DateTime<Utc>) {
     if let Some((start, end)) =
@@ -81,38 +485,115 @@ fn get_start_stop_at_date(
     )
 }
 
-async fn update_monitors_from_time<'a>(disps: &mut Displays<'a>, cfg: &Config) {
+/// The phase the sun is in, along with when it started.
+fn current_phase(geo: &Location) -> (Phase, DateTime<Utc>) {
     let now = Local::now();
     // return _today's_ sunrise and sunset times.
-    let geo = get_start_stop_at_date(&cfg.geo, now.date_naive());
-    let is_daytime = now > geo.0 && now < geo.1;
-    info!(
-        "updating brightness of all displays to {} value",
-        if is_daytime { "daytime" } else { "nighttime" }
-    );
+    let (sunrise, sunset) = get_start_stop_at_date(geo, now.date_naive());
+    if now > sunrise && now < sunset {
+        (Phase::Day, sunrise)
+    } else if now >= sunset {
+        (Phase::Night, sunset)
+    } else {
+        let yesterday = (now - Duration::days(1)).date_naive();
+        (Phase::Night, get_start_stop_at_date(geo, yesterday).1)
+    }
+}
+
+/// Sets every display to its brightness for the current phase, which is returned.
+async fn update_monitors_from_time<'a>(
+    disps: &Displays<'a>,
+    geo: &Location,
+    state: &mut State,
+    hooks: &Hooks,
+    resumed: bool,
+) -> Phase {
+    let (phase, started) = current_phase(geo);
+    info!("updating brightness of all displays to {} value", phase);
+
+    // Overrides from earlier phases have ended, including those from the same phase on
+    // an earlier day.
+    for d in disps.iter() {
+        let ended = state.displays.get_mut(d.identity()).filter(|s| {
+            s.brightness_override
+                .is_some_and(|o| !o.is_active(phase, started))
+        });
+        if let Some(s) = ended {
+            debug!("the override of {} ended as it's now {}", d, phase);
+            s.brightness_override = None;
+            hooks.fire(Event::OverrideEnd {
+                display: d.identity().to_owned(),
+                bus: d.bus().to_owned(),
+            });
+        }
+    }
+
+    // Overrides from before a restart still apply if their phase hasn't ended.
+    let targets = disps
+        .iter()
+        .map(|d| {
+            let saved = state.get(d.identity()).cloned().unwrap_or_default();
+            let target = saved.target_for(
+                d.target_brightness(phase.is_daytime()) * 100.0,
+                phase,
+                started,
+            ) / 100.0;
+            // The display was left at the target when the daemon last stopped.
+            let skip = resumed && saved.applied(target * 100.0, phase);
+            (d, target, skip)
+        })
+        .collect::<Vec<_>>();
 
     // Run all updates in parallel, retrying, and if any error bail completely.
     // When resuming from suspend, monitors may not wake up consistently and this
     // ensures they eventually are set properly.
+    let updates = targets.iter().map(|&(d, target, skip)| async move {
+        if skip {
+            info!(
+                "{} was already set to {:.0}% before restarting",
+                d,
+                target * 100.0
+            );
+            return Ok(WriteOutcome::Skipped);
+        }
+        retry_monitor(d, target, hooks).await
+    });
     select! {
-        res = try_join_all(disps.iter_mut().map(|d| retry_monitor(d, is_daytime))) => match res {
+        res = try_join_all(updates) => match res {
             Err(e) => {
                 error!("failed to set display brightness: {}", e);
                 panic!("{}", e);
             },
-            Ok(_) => {
+            Ok(outcomes) => {
                 debug!("finished setting monitor brightness");
+                for ((d, target, _), outcome) in targets.iter().zip(outcomes) {
+                    let s = state.display(d.identity());
+                    s.target = Some(target * 100.0);
+                    s.phase = Some(phase);
+                    s.observed = match outcome {
+                        WriteOutcome::Written => None,
+                        WriteOutcome::Mismatched { .. } => None,
+                        WriteOutcome::Skipped | WriteOutcome::Verified => Some(target * 100.0),
+                    };
+                }
             },
         },
         _ = sleep(std::time::Duration::from_secs(300)) => {
             error!("timed out setting monitor brightness after 5 mintues");
         }
     };
+    phase
 }
 
+/// Writes which don't read back the target before giving up on it. Displays which round
+/// to coarse steps never will, and each write may wear their EEPROM.
+const MAX_MISMATCHED_WRITES: usize = 3;
+
 /// retry_monitor retires setting brightness on failure indefinely. It's not expected
-/// that errors should return except when dependencies fail.
-async fn retry_monitor<'a>(disp: &mut Display<'a>, is_day: bool) -> Result<()> {
+/// that errors should return except when dependencies fail. Writes which read back
+/// something else are only retried a few times.
+/// The first failure of each call is passed on to hooks.
+async fn retry_monitor<'a>(disp: &Display<'a>, target: f64, hooks: &Hooks) -> Result<WriteOutcome> {
     let mut backoff = ExponentialBackoffBuilder::default()
         .factor(1.1)
         .min(std::time::Duration::from_secs(0))
@@ -122,11 +603,39 @@ async fn retry_monitor<'a>(disp: &mut Display<'a>, is_day: bool) -> Result<()> {
         .unwrap();
 
     let mut tries: usize = 1;
-    while let Err(e) = disp.update_brightness(is_day) {
-        debug!(
-            "failed to set brightness for {} on try {}: {}",
-            disp, tries, e
-        );
+    let mut mismatches = 0;
+    loop {
+        // A display which doesn't read back what was written likely hasn't woken up yet.
+        match disp.with_device(move |d| d.set_brightness(target)).await {
+            Ok(outcome @ WriteOutcome::Mismatched { .. }) => {
+                mismatches += 1;
+                if mismatches >= MAX_MISMATCHED_WRITES {
+                    warn!(
+                        "brightness for {} was {} {} times, leaving it",
+                        disp, outcome, mismatches
+                    );
+                    return Ok(outcome);
+                }
+                debug!("brightness for {} on try {} was {}", disp, tries, outcome)
+            }
+            Ok(outcome) => {
+                info!("updated brightness for {}: {}", disp, outcome);
+                return Ok(outcome);
+            }
+            Err(e) => {
+                debug!(
+                    "failed to set brightness for {} on try {}: {}",
+                    disp, tries, e
+                );
+                if tries == 1 {
+                    hooks.fire(Event::DdcFailure {
+                        display: disp.identity().to_owned(),
+                        bus: disp.bus().to_owned(),
+                        error: format!("{:#}", e),
+                    });
+                }
+            }
+        }
         let delay = backoff.fail();
         tries += 1;
         trace!(
@@ -137,6 +646,4 @@ async fn retry_monitor<'a>(disp: &mut Display<'a>, is_day: bool) -> Result<()> {
 
         sleep(delay).await;
     }
-
-    Ok(())
 }
@@ -1,14 +1,25 @@
+use std::collections::{BTreeMap, HashMap};
 use std::convert::{TryFrom, TryInto};
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use structopt::StructOpt;
 
-use crate::{logging::*, prelude::*, types::*};
+use crate::{
+    display::{adapter_name, BrightnessCurve, Luminance, Response, Scale, NON_DDC_ADAPTERS},
+    location::{
+        check_latitude, check_longitude, locate_city, parse_latitude, parse_location,
+        parse_longitude, system_timezone, timezone_coordinates, Location,
+    },
+    logging::*,
+    prelude::*,
+    types::*,
+};
 
 #[derive(StructOpt, Debug, Deserialize)]
 pub struct Opts {
     #[structopt(flatten)]
+    #[serde(default)]
     pub geo: GeoOpts,
 
     #[serde(flatten)]
@@ -16,6 +27,7 @@ pub struct Opts {
     pub brightness: BrightnessOpts,
 
     #[structopt(flatten)]
+    #[serde(default)]
     pub logging: crate::logging::LogOpts,
 
     /// exclusively manage devices matched by the devices list.
@@ -26,84 +38,1616 @@ pub struct Opts {
     #[serde(default)]
     pub device_match_exclusive: bool,
 
-    /// device matchers with optional device-specific overrides.
-    #[structopt(skip)]
-    pub devices: Vec<DeviceOpts>,
+    /// device matchers with optional device-specific overrides.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub devices: Vec<DeviceOpts>,
+
+    /// named device matchers which may be referenced from other matchers with
+    /// `alias: <name>`.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub aliases: HashMap<String, DeviceMatcher>,
+
+    #[serde(flatten)]
+    #[structopt(skip)]
+    pub discovery: DiscoveryOpts,
+
+    /// file the daemon keeps display state in across restarts. Defaults to
+    /// state.yaml in $STATE_DIRECTORY, $XDG_STATE_HOME/dlux, or ~/.local/state/dlux.
+    #[structopt(long, env = "DLUX_STATE_FILE", parse(from_os_str))]
+    #[serde(default)]
+    pub state_file: Option<std::path::PathBuf>,
+
+    /// restore each display to its brightness from before dlux changed it
+    /// when the daemon is stopped.
+    #[structopt(long)]
+    #[serde(default)]
+    pub restore_brightness_on_exit: bool,
+
+    /// watches displays for brightness changed with their buttons. Changes are
+    /// overwritten at the next event if unset.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub manual_changes: Option<ManualChangeOpts>,
+
+    /// follows the position reported by gpsd instead of the fixed location, which is
+    /// used until there's a fix.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub gpsd: Option<GpsdOpts>,
+
+    /// named sets of brightness rules which may be switched between while the daemon
+    /// runs. The top-level rules are the "default" profile.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub profiles: BTreeMap<String, ProfileOpts>,
+
+    /// profile used when the daemon first starts.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub profile: Option<String>,
+
+    /// unix socket the daemon accepts commands on. Defaults to control.sock in
+    /// $RUNTIME_DIRECTORY, $XDG_RUNTIME_DIR/dlux, or /run/dlux.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub control_socket: Option<std::path::PathBuf>,
+
+    /// commands run when the daemon changes brightness or notices changes to displays.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub hooks: Vec<HookOpts>,
+
+    /// most hook commands which may run at once. Others wait for one to finish.
+    #[structopt(skip)]
+    #[serde(default)]
+    pub max_concurrent_hooks: Option<usize>,
+
+    /// problems found while reading the options which don't stop them being used.
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub warnings: Vec<String>,
+
+    /// what the options were read from, so problems found in them can be pointed to.
+    #[structopt(skip)]
+    #[serde(skip)]
+    pub sources: Sources,
+}
+
+/// Name of the profile made up of the top-level brightness and devices.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// a set of brightness rules. Brightness which isn't set falls back to the top-level
+/// brightness, while devices replace the top-level devices entirely.
+#[derive(Debug, Default, Deserialize)]
+pub struct ProfileOpts {
+    #[serde(flatten)]
+    pub brightness: BrightnessOpts,
+    #[serde(default)]
+    pub device_match_exclusive: Option<bool>,
+    #[serde(default)]
+    pub devices: Vec<DeviceOpts>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ManualChangeOpts {
+    /// how often displays are checked for changes.
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    #[serde(default)]
+    pub policy: ManualChangePolicy,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GpsdOpts {
+    /// host and port gpsd listens on.
+    #[serde(default = "default_gpsd_address")]
+    pub address: String,
+    /// how far the position must move before sunrise and sunset are recalculated.
+    #[serde(default = "default_gpsd_min_distance_km")]
+    pub min_distance_km: f64,
+    /// how long the last fix is used once gpsd stops reporting one, before falling
+    /// back to the configured location.
+    #[serde(default = "default_gpsd_max_fix_age_mins")]
+    pub max_fix_age_mins: u64,
+}
+
+/// a command run with sh when any of the events happen. The event is given as JSON on
+/// stdin and as DLUX_* environment variables.
+#[derive(Debug, Clone, Deserialize)]
+pub struct HookOpts {
+    /// names of the events the command runs on, or every event if empty.
+    #[serde(default)]
+    pub events: Vec<String>,
+    pub command: String,
+    /// how long the command may run before it's killed.
+    #[serde(default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    30
+}
+
+/// Number of hook commands which may run at once unless configured.
+pub const DEFAULT_MAX_CONCURRENT_HOOKS: usize = 4;
+
+fn default_gpsd_address() -> String {
+    "127.0.0.1:2947".to_owned()
+}
+
+fn default_gpsd_min_distance_km() -> f64 {
+    10.0
+}
+
+fn default_gpsd_max_fix_age_mins() -> u64 {
+    60
+}
+
+/// what to do when a display's brightness was changed outside of dlux.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ManualChangePolicy {
+    /// keep the new brightness until the next sunrise or sunset.
+    #[default]
+    Keep,
+    /// shift day and night brightness by the change until it's changed again.
+    Shift,
+    /// set the display back to its target right away.
+    Enforce,
+}
+
+/// Config file formats. They share the same schema.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl Format {
+    /// Detects the format from the file's extension. Files without a known extension
+    /// are YAML.
+    pub fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("toml") => Self::Toml,
+            Some(ext) if ext.eq_ignore_ascii_case("json") => Self::Json,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "yaml" | "yml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            "json" => Ok(Self::Json),
+            _ => Err(format_err!(
+                "unknown config format {:?}, expected yaml, toml, or json",
+                s
+            )),
+        }
+    }
+}
+
+/// Where config files are searched for when none is given, after $DLUX_CONFIG.
+fn config_candidates() -> Vec<std::path::PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+    let xdg = var("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| var("HOME").map(|h| std::path::PathBuf::from(h).join(".config")));
+
+    xdg.map(|d| d.join("dlux/config.yaml"))
+        .into_iter()
+        .chain(std::iter::once("/etc/dlux/config.yaml".into()))
+        .collect()
+}
+
+/// Directory of config fragments next to a config file, merged on top of it.
+const DROP_IN_DIR: &str = "config.d";
+
+impl Opts {
+    /// Finds the config file to use when none is given. $DLUX_CONFIG is used if set,
+    /// then $XDG_CONFIG_HOME/dlux/config.yaml and /etc/dlux/config.yaml if they exist.
+    pub fn find() -> Result<std::path::PathBuf> {
+        if let Some(path) = std::env::var_os("DLUX_CONFIG").filter(|v| !v.is_empty()) {
+            return Ok(path.into());
+        }
+
+        let candidates = config_candidates();
+        candidates
+            .iter()
+            .find(|p| p.is_file())
+            .cloned()
+            .ok_or_else(|| {
+                format_err!(
+                    "no config file was given and none was found at $DLUX_CONFIG, {}",
+                    candidates
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    /// Reads options from a file in the format, or detected from its extension if
+    /// None. Fragments in config.d next to the file are merged on top of it in lexical
+    /// order, whether or not the file is one of the default ones, and each fragment's
+    /// format is detected from its own extension. Parse errors are prefixed with the
+    /// path, line, and column they occurred at, as each file and variable is checked
+    /// before they're merged.
+    pub fn from_file(path: &std::path::Path, format: Option<Format>) -> Result<Self> {
+        Self::from_file_with_vars(path, format, env_vars())
+    }
+
+    fn from_file_with_vars(
+        path: &std::path::Path,
+        format: Option<Format>,
+        vars: Vec<(String, String)>,
+    ) -> Result<Self> {
+        let format = format.unwrap_or_else(|| Format::from_path(path));
+        let drop_ins = drop_ins(path)?;
+        let mut warnings = vec![];
+        let env = env_overrides(
+            vars.clone(),
+            &[ENV_FLAG_OPTIONS, ENV_OPTIONS].concat(),
+            &mut warnings,
+        )?;
+        let source = Source::read(path, format)?;
+        if drop_ins.is_empty() && env.is_empty() {
+            let opts: Opts = source.parse()?;
+            return Ok(Opts {
+                warnings,
+                sources: Sources(vec![Fragment::file(source)?]),
+                ..opts
+            });
+        }
+
+        let mut fragments = vec![Fragment::file(source)?];
+        for drop_in in drop_ins.iter() {
+            debug!("merging config fragment {:?}", drop_in);
+            fragments.push(Fragment::file(Source::read(
+                drop_in,
+                Format::from_path(drop_in),
+            )?)?);
+        }
+        for (name, keys, value) in env.iter() {
+            let raw = vars
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.clone())
+                .unwrap_or_default();
+            fragments.push(Fragment::var(name, raw, keys, value.clone())?);
+        }
+
+        let mut merged = serde_yaml::Value::Null;
+        for fragment in fragments.iter() {
+            match fragment.at {
+                [] => merge(&mut merged, fragment.value.clone()),
+                keys => set_key(&mut merged, keys, fragment.value.clone()),
+            }
+        }
+
+        let opts: Opts = serde_yaml::from_value(merged).map_err(|e| {
+            format_err!(
+                "{} merged with {}: {}",
+                path.display(),
+                drop_ins
+                    .iter()
+                    .map(|p| p.display().to_string())
+                    .chain(env.iter().map(|(name, _, _)| format!("${}", name)))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                e
+            )
+        })?;
+        Ok(Opts {
+            warnings,
+            sources: Sources(fragments),
+            ..opts
+        })
+    }
+
+    /// Sets the options `start` has no flags taking a value for from DLUX_*
+    /// environment variables. Options with flags read the environment when they're
+    /// parsed, and the flags take precedence.
+    pub fn with_env(self) -> Result<Self> {
+        self.with_vars(env_vars())
+    }
+
+    fn with_vars(mut self, vars: Vec<(String, String)>) -> Result<Self> {
+        let env = env_overrides(vars, ENV_OPTIONS, &mut self.warnings)?;
+        if env.is_empty() {
+            return Ok(self);
+        }
+
+        // geo is required, so it's passed through from the command line.
+        let mut base = serde_yaml::Value::Null;
+        set_key(&mut base, &["geo"], serde_yaml::to_value(&self.geo)?);
+        for (_, keys, value) in env.iter() {
+            set_key(&mut base, keys, value.clone());
+        }
+        let env: Opts = serde_yaml::from_value(base).map_err(|e| {
+            format_err!(
+                "{}: {}",
+                env.iter()
+                    .map(|(name, _, _)| format!("${}", name))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                e
+            )
+        })?;
+
+        Ok(Opts {
+            device_match_exclusive: env.device_match_exclusive,
+            devices: env.devices,
+            aliases: env.aliases,
+            discovery: env.discovery,
+            restore_brightness_on_exit: self.restore_brightness_on_exit
+                || env.restore_brightness_on_exit,
+            manual_changes: env.manual_changes,
+            gpsd: env.gpsd,
+            profiles: env.profiles,
+            profile: env.profile,
+            control_socket: env.control_socket,
+            hooks: env.hooks,
+            max_concurrent_hooks: env.max_concurrent_hooks,
+            ..self
+        })
+    }
+}
+
+/// Prefix of the environment variables which set options.
+const ENV_PREFIX: &str = "DLUX_";
+
+/// Options which may be set by environment variables, as the keys leading to them in a
+/// config file. Variables are named after the keys, like DLUX_GEO_LATITUDE. These
+/// options have flags for `start`, which read the environment themselves.
+const ENV_FLAG_OPTIONS: &[&[&str]] = &[
+    &["geo", "latitude"],
+    &["geo", "longitude"],
+    &["geo", "altitude"],
+    &["geo", "location"],
+    &["geo", "city"],
+    &["geo", "timezone"],
+    &["day_brightness"],
+    &["night_brightness"],
+    &["scale"],
+    &["min_brightness"],
+    &["logging", "level"],
+    &["logging", "style"],
+    &["state_file"],
+];
+
+/// Options which may be set by environment variables and have no flags taking a value.
+const ENV_OPTIONS: &[&[&str]] = &[
+    &["device_match_exclusive"],
+    &["devices"],
+    &["aliases"],
+    &["edid_overrides"],
+    &["buses"],
+    &["ddc"],
+    &["restore_brightness_on_exit"],
+    &["manual_changes"],
+    &["gpsd"],
+    &["profiles"],
+    &["profile"],
+    &["control_socket"],
+    &["hooks"],
+    &["max_concurrent_hooks"],
+];
+
+fn env_name(keys: &[&str]) -> String {
+    format!("{}{}", ENV_PREFIX, keys.join("_").to_uppercase())
+}
+
+/// The environment variables with the prefix. Those which aren't unicode can't be
+/// options, so they're left out.
+fn env_vars() -> Vec<(String, String)> {
+    std::env::vars_os()
+        .filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?)))
+        .filter(|(k, _)| k.starts_with(ENV_PREFIX))
+        .collect()
+}
+
+/// Parses the variables which set the options, returning each one's name, keys, and
+/// value. Values are YAML, so lists and mappings are given in flow style, like
+/// `[{model: U2415}]`. Empty variables are ignored, and those with the prefix which
+/// don't name an option are warned about, as hooks set variables like DLUX_EVENT for
+/// the commands they run.
+fn env_overrides<I: IntoIterator<Item = (String, String)>>(
+    vars: I,
+    options: &[&'static [&'static str]],
+    warnings: &mut Vec<String>,
+) -> Result<Vec<(String, &'static [&'static str], serde_yaml::Value)>> {
+    let mut vars: Vec<_> = vars
+        .into_iter()
+        .filter(|(name, value)| name.starts_with(ENV_PREFIX) && !value.is_empty())
+        .collect();
+    vars.sort();
+
+    let mut overrides = vec![];
+    let mut problems = vec![];
+    for (name, value) in vars {
+        let keys = ENV_FLAG_OPTIONS
+            .iter()
+            .chain(ENV_OPTIONS)
+            .find(|keys| env_name(keys) == name);
+        match keys {
+            Some(keys) if options.contains(keys) => match serde_yaml::from_str(&value) {
+                Ok(value) => overrides.push((name, *keys, value)),
+                Err(e) => problems.push(format!("${}: {}", name, e)),
+            },
+            Some(_) => (),
+            // set by the environment, but not an option
+            None if name == "DLUX_CONFIG" => (),
+            None => warnings.push(format!("${} is not an option, so it's ignored", name)),
+        }
+    }
+
+    if !problems.is_empty() {
+        return Err(format_err!("{}", problems.join("\n")));
+    }
+    Ok(overrides)
+}
+
+/// Sets the value at the keys, replacing whatever was there. Missing mappings along
+/// the way are created.
+fn set_key(base: &mut serde_yaml::Value, keys: &[&str], value: serde_yaml::Value) {
+    use serde_yaml::Value;
+
+    match keys.split_first() {
+        None => *base = value,
+        Some((key, rest)) => {
+            if !base.is_mapping() {
+                *base = Value::Mapping(Default::default());
+            }
+            let map = base.as_mapping_mut().unwrap();
+            let key = Value::String((*key).to_owned());
+            match map.get_mut(&key) {
+                Some(existing) => set_key(existing, rest, value),
+                None => {
+                    let mut new = Value::Null;
+                    set_key(&mut new, rest, value);
+                    map.insert(key, new);
+                }
+            }
+        }
+    }
+}
+
+/// Lists config fragments in the drop-in directory next to path in lexical order.
+/// Files in formats other than those supported are ignored.
+fn drop_ins(path: &std::path::Path) -> Result<Vec<std::path::PathBuf>> {
+    let dir = path
+        .parent()
+        .unwrap_or_else(|| std::path::Path::new("."))
+        .join(DROP_IN_DIR);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).with_context(|| format!("failed to list {:?}", dir)),
+    };
+
+    let mut paths = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let supported = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(|e| e.parse::<Format>().is_ok())
+            .unwrap_or(false);
+        if path.is_file() && supported {
+            paths.push(path);
+        } else {
+            trace!("ignoring {:?} in {:?}", path, dir);
+        }
+    }
+
+    paths.sort();
+    Ok(paths)
+}
+
+/// Merges a config fragment into base. Mappings are merged by key and other values
+/// are replaced, except for lists: the fragment's entries are put before base's, so
+/// rules it adds take priority.
+fn merge(base: &mut serde_yaml::Value, fragment: serde_yaml::Value) {
+    use serde_yaml::Value;
+
+    match (base, fragment) {
+        // an empty file
+        (_, Value::Null) => (),
+        (Value::Mapping(base), Value::Mapping(fragment)) => {
+            for (k, v) in fragment {
+                match base.get_mut(&k) {
+                    Some(existing) => merge(existing, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (Value::Sequence(base), Value::Sequence(mut fragment)) => {
+            fragment.append(base);
+            *base = fragment;
+        }
+        (base, fragment) => *base = fragment,
+    }
+}
+
+/// What options were read from, in the order they're merged, so problems found in
+/// them can be pointed to.
+#[derive(Debug, Default)]
+pub struct Sources(Vec<Fragment>);
+
+impl Sources {
+    /// Names where the option at the keys was set: the file or variable, and the line
+    /// and column in it if it's there. Options set by more than one file are pointed
+    /// to where they're first set, unless they're replaced. None if the options
+    /// weren't read from files.
+    fn locate(&self, keys: &[Key]) -> Option<String> {
+        let (first, mut at) = (self.0.first()?, keys.to_vec());
+        let mut owner = None;
+        for fragment in self.0.iter().rev() {
+            if !fragment.at.is_empty() {
+                // variables replace whatever they set
+                let set = at.len() >= fragment.at.len()
+                    && at.iter().zip(fragment.at).all(|(key, name)| match key {
+                        Key::Name(key) => key == name,
+                        Key::Index(_) => false,
+                    });
+                if set {
+                    return Some(fragment.source.locate(&at[fragment.at.len()..]));
+                }
+                continue;
+            }
+
+            match fragment.sets(&at) {
+                Sets::Nothing => (),
+                Sets::Replaced => return Some(fragment.source.locate(&at)),
+                Sets::Merged => owner = Some((fragment, at.clone())),
+                Sets::Before { depth, entries } => {
+                    if let Key::Index(i) = &mut at[depth] {
+                        *i -= entries;
+                    }
+                }
+            }
+        }
+
+        let (fragment, at) = owner.unwrap_or((first, keys.to_vec()));
+        Some(fragment.source.locate(&at))
+    }
+}
+
+/// Options read from a file, or set by an environment variable.
+#[derive(Debug)]
+struct Fragment {
+    source: Source,
+    /// the keys of the option a variable sets, or empty for a file.
+    at: &'static [&'static str],
+    value: serde_yaml::Value,
+}
+
+/// How a fragment sets an option when it's merged.
+enum Sets {
+    Nothing,
+    /// it replaces what earlier fragments set.
+    Replaced,
+    /// it's merged with what earlier fragments set.
+    Merged,
+    /// a list on the way to the option has fewer entries than its index. They're
+    /// put before earlier fragments' entries.
+    Before {
+        depth: usize,
+        entries: usize,
+    },
+}
+
+impl Fragment {
+    /// Parses a file, checking its options are valid on their own so errors are
+    /// reported where they are.
+    fn file(source: Source) -> Result<Self> {
+        let value: serde_yaml::Value = source.parse()?;
+        // an empty file
+        if !value.is_null() {
+            source.parse::<Opts>()?;
+        }
+        Ok(Fragment {
+            source,
+            at: &[],
+            value,
+        })
+    }
+
+    /// Checks the value of a variable setting the option at the keys is valid on its
+    /// own, so errors name the variable.
+    fn var(
+        name: &str,
+        raw: String,
+        keys: &'static [&'static str],
+        value: serde_yaml::Value,
+    ) -> Result<Self> {
+        let mut alone = serde_yaml::Value::Null;
+        set_key(&mut alone, keys, value.clone());
+        serde_yaml::from_value::<Opts>(alone).map_err(|e| format_err!("${}: {}", name, e))?;
+        Ok(Fragment {
+            source: Source {
+                name: format!("${}", name),
+                raw,
+                format: Format::Yaml,
+            },
+            at: keys,
+            value,
+        })
+    }
+
+    fn sets(&self, keys: &[Key]) -> Sets {
+        use serde_yaml::Value;
+
+        let mut value = &self.value;
+        for (depth, key) in keys.iter().enumerate() {
+            value = match (value, key) {
+                (Value::Mapping(map), Key::Name(name)) => match map.get(name.as_str()) {
+                    Some(value) if !value.is_null() => value,
+                    _ => return Sets::Nothing,
+                },
+                // entries of lists aren't merged
+                (Value::Sequence(list), Key::Index(i)) if *i < list.len() => return Sets::Replaced,
+                (Value::Sequence(list), Key::Index(_)) => {
+                    return Sets::Before {
+                        depth,
+                        entries: list.len(),
+                    }
+                }
+                _ => return Sets::Nothing,
+            };
+        }
+        if value.is_mapping() || value.is_sequence() {
+            Sets::Merged
+        } else {
+            Sets::Replaced
+        }
+    }
+}
+
+/// A config file options were read from.
+#[derive(Debug)]
+struct Source {
+    name: String,
+    raw: String,
+    format: Format,
+}
+
+impl Source {
+    fn read(path: &std::path::Path, format: Format) -> Result<Self> {
+        let raw = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {:?}", path))?;
+        Ok(Source {
+            name: path.display().to_string(),
+            raw,
+            format,
+        })
+    }
+
+    /// Parses the options, prefixing errors with the file, line, and column they
+    /// occurred at.
+    fn parse<T: serde::de::DeserializeOwned>(&self) -> Result<T> {
+        parse(&self.raw, self.format).map_err(|(msg, pos)| match pos {
+            Some((line, column)) => format_err!("{}:{}:{}: {}", self.name, line, column, msg),
+            None => format_err!("{}: {}", self.name, msg),
+        })
+    }
+
+    /// Names the file, and the line and column of the option at the keys if it's set.
+    fn locate(&self, keys: &[Key]) -> String {
+        match position(&self.raw, self.format, keys) {
+            Some((line, column)) => format!("{}:{}:{}", self.name, line, column),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// A step along the path to an option: a key of a mapping or an index into a list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Name(String),
+    Index(usize),
+}
+
+/// The path to the option under the names, like `keys(&["geo", "latitude"])`.
+fn keys(names: &[&str]) -> Vec<Key> {
+    names.iter().map(|n| Key::Name((*n).to_owned())).collect()
+}
+
+/// An error message, and its line and column if known.
+type ParseError = (String, Option<(usize, usize)>);
+
+/// Parses options, returning the error message and its line and column if known.
+fn parse<T: serde::de::DeserializeOwned>(
+    raw: &str,
+    format: Format,
+) -> std::result::Result<T, ParseError> {
+    deserialize(raw, format, std::marker::PhantomData)
+}
+
+/// Finds the line and column of the option at the keys. Each format tells where an
+/// error occurred, so it's deserialized down to the option, failing there. None if
+/// the option isn't set.
+fn position(raw: &str, format: Format, keys: &[Key]) -> Option<(usize, usize)> {
+    match deserialize(raw, format, Seek(keys)) {
+        Err((msg, pos)) if msg.ends_with(FOUND) => pos,
+        _ => None,
+    }
+}
+
+fn deserialize<'de, S: serde::de::DeserializeSeed<'de>>(
+    raw: &'de str,
+    format: Format,
+    seed: S,
+) -> std::result::Result<S::Value, ParseError> {
+    match format {
+        Format::Yaml => seed
+            .deserialize(serde_yaml::Deserializer::from_str(raw))
+            .map_err(|e| {
+                let pos = e.location().map(|l| (l.line(), l.column()));
+                let msg = e.to_string();
+                // The location is already in the prefix.
+                let msg = match msg.rfind(" at line ") {
+                    Some(i) if pos.is_some() => msg[..i].to_owned(),
+                    _ => msg,
+                };
+                (msg, pos)
+            }),
+        Format::Toml => seed.deserialize(toml::Deserializer::new(raw)).map_err(|e| {
+            let pos = e.span().map(|span| line_column(raw, span.start));
+            (e.message().to_owned(), pos)
+        }),
+        Format::Json => {
+            let mut de = serde_json::Deserializer::from_str(raw);
+            seed.deserialize(&mut de)
+                .and_then(|value| de.end().map(|()| value))
+        }
+        .map_err(|e| {
+            let pos = Some((e.line(), e.column())).filter(|(line, _)| *line != 0);
+            let msg = e.to_string();
+            let msg = match msg.rfind(" at line ") {
+                Some(i) if pos.is_some() => msg[..i].to_owned(),
+                _ => msg,
+            };
+            (msg, pos)
+        }),
+    }
+}
+
+/// Message Seek fails with once it reaches the option.
+const FOUND: &str = "found the option";
+
+/// Deserializes down to the option at the keys, skipping everything else, and fails
+/// there with FOUND.
+struct Seek<'a>(&'a [Key]);
+
+impl Seek<'_> {
+    fn scalar<E: serde::de::Error>(self) -> std::result::Result<(), E> {
+        match self.0 {
+            [] => Err(E::custom(FOUND)),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<'de> serde::de::DeserializeSeed<'de> for Seek<'_> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        deserializer.deserialize_any(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for Seek<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E: serde::de::Error>(self, _: bool) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, _: i64) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, _: u64) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_f64<E: serde::de::Error>(self, _: f64) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_str<E: serde::de::Error>(self, _: &str) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_unit<E: serde::de::Error>(self) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_none<E: serde::de::Error>(self) -> std::result::Result<(), E> {
+        self.scalar()
+    }
+
+    fn visit_some<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<(), D::Error> {
+        serde::de::DeserializeSeed::deserialize(self, deserializer)
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<(), A::Error> {
+        use serde::de::{Error, IgnoredAny};
+
+        let (index, rest) = match self.0.split_first() {
+            None => return Err(A::Error::custom(FOUND)),
+            Some((Key::Index(index), rest)) => (*index, rest),
+            Some(_) => return Ok(()),
+        };
+        for _ in 0..index {
+            if seq.next_element::<IgnoredAny>()?.is_none() {
+                return Ok(());
+            }
+        }
+        seq.next_element_seed(Seek(rest)).map(|_| ())
+    }
+
+    fn visit_map<A: serde::de::MapAccess<'de>>(
+        self,
+        mut map: A,
+    ) -> std::result::Result<(), A::Error> {
+        use serde::de::{Error, IgnoredAny};
+
+        let (name, rest) = match self.0.split_first() {
+            None => return Err(A::Error::custom(FOUND)),
+            Some((Key::Name(name), rest)) => (name, rest),
+            Some(_) => return Ok(()),
+        };
+        while let Some(key) = map.next_key::<serde_yaml::Value>()? {
+            if key.as_str() == Some(name) {
+                return map.next_value_seed(Seek(rest));
+            }
+            map.next_value::<IgnoredAny>()?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the 1-based line and column of the byte offset in s.
+fn line_column(s: &str, offset: usize) -> (usize, usize) {
+    let before = &s[..offset.min(s.len())];
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// controls how displays are found and identified.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DiscoveryOpts {
+    /// replaces the identity reported by specific devices. Useful when monitors
+    /// share an EDID or a switch in between returns garbage.
+    #[serde(default)]
+    pub edid_overrides: Vec<EdidOverride>,
+
+    /// selects which i2c buses are probed for displays.
+    #[serde(default)]
+    pub buses: BusOpts,
+
+    /// paces DDC/CI commands sent to displays.
+    #[serde(default)]
+    pub ddc: DdcOpts,
+}
+
+/// delays between DDC/CI commands on a bus. The MCCS minimums for each command always
+/// apply, so these only lengthen them for slow displays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct DdcTiming {
+    /// minimum time from the end of one command to the start of the next.
+    #[serde(default = "default_command_delay_ms")]
+    pub command_delay_ms: u64,
+    /// time waited after sending a request before reading the display's reply.
+    #[serde(default = "default_reply_delay_ms")]
+    pub reply_delay_ms: u64,
+}
+
+impl Default for DdcTiming {
+    fn default() -> Self {
+        Self {
+            command_delay_ms: default_command_delay_ms(),
+            reply_delay_ms: default_reply_delay_ms(),
+        }
+    }
+}
+
+fn default_command_delay_ms() -> u64 {
+    50
+}
+
+fn default_reply_delay_ms() -> u64 {
+    50
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DdcOpts {
+    #[serde(flatten)]
+    pub timing: DdcTiming,
+    /// timing overrides for displays which need longer delays than the rest. The
+    /// first matching quirk applies.
+    #[serde(default)]
+    pub quirks: Vec<DdcQuirk>,
+}
+
+impl DdcOpts {
+    /// Returns the timing for the display, including any quirk which matches it.
+    pub fn timing_for(&self, info: &DeviceInfo) -> DdcTiming {
+        match self.quirks.iter().find(|q| q.matcher.matches(info)) {
+            Some(q) => DdcTiming {
+                command_delay_ms: q.command_delay_ms.unwrap_or(self.timing.command_delay_ms),
+                reply_delay_ms: q.reply_delay_ms.unwrap_or(self.timing.reply_delay_ms),
+            },
+            None => self.timing,
+        }
+    }
+}
+
+/// overrides DDC/CI timing for displays matching the matcher.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct DdcQuirk {
+    #[serde(flatten)]
+    pub matcher: DeviceMatcher,
+    #[serde(default)]
+    pub command_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub reply_delay_ms: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct BusOpts {
+    /// only probe buses matching at least one of these, if any are given.
+    #[serde(default)]
+    pub include: Vec<BusFilter>,
+    /// never probe buses matching any of these.
+    #[serde(default)]
+    pub exclude: Vec<BusFilter>,
+    /// skip adapters known not to have displays on them unless they're
+    /// explicitly included.
+    #[serde(default = "default_true")]
+    pub skip_non_ddc: bool,
+}
+
+impl Default for BusOpts {
+    fn default() -> Self {
+        Self {
+            include: vec![],
+            exclude: vec![],
+            skip_non_ddc: true,
+        }
+    }
+}
+
+impl BusOpts {
+    /// Ok if the bus at path with the passed adapter name should be probed,
+    /// otherwise Err with the reason it's skipped.
+    pub fn allows(&self, path: &str, adapter: &str) -> std::result::Result<(), String> {
+        let included = self.include.iter().any(|f| f.matches(path, adapter));
+        if !self.include.is_empty() && !included {
+            return Err("not included".to_owned());
+        }
+        if let Some(f) = self.exclude.iter().find(|f| f.matches(path, adapter)) {
+            return Err(format!("excluded by {}", f));
+        }
+        if self.skip_non_ddc && !included && NON_DDC_ADAPTERS.iter().any(|p| adapter.starts_with(p))
+        {
+            return Err("adapter isn't used for displays".to_owned());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum BusFilter {
+    /// glob matched against the bus path, e.g. /dev/i2c-1*, or its name, e.g. i2c-3.
+    Path(#[serde(deserialize_with = "deserialize_glob")] glob::Pattern),
+    /// regular expression matched against the adapter name, e.g. (?i)amdgpu.
+    Adapter {
+        #[serde(with = "serde_regex")]
+        adapter: Regex,
+    },
+}
+
+impl BusFilter {
+    fn matches(&self, path: &str, adapter: &str) -> bool {
+        match self {
+            Self::Path(p) => p.matches(path) || p.matches(adapter_name(path)),
+            Self::Adapter { adapter: re } => re.is_match(adapter),
+        }
+    }
+}
+
+impl std::fmt::Display for BusFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Path(p) => write!(f, "path {}", p),
+            Self::Adapter { adapter } => write!(f, "adapter {}", adapter),
+        }
+    }
+}
+
+fn deserialize_glob<'de, D: serde::Deserializer<'de>>(d: D) -> Result<glob::Pattern, D::Error> {
+    let pattern = String::deserialize(d)?;
+    glob::Pattern::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// replaces the EDID read from the device on a bus or connector with one read from
+/// a file, explicit field values, or both. Explicit fields are applied last.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct EdidOverride {
+    /// i2c bus of the device to override, e.g. /dev/i2c-3 or i2c-3.
+    #[serde(default)]
+    pub bus: Option<String>,
+    /// DRM connector of the device to override, e.g. DP-2 or card0-DP-2.
+    #[serde(default)]
+    pub connector: Option<String>,
+    /// path to a binary EDID file used in place of the device's EDID.
+    #[serde(default)]
+    pub edid: Option<std::path::PathBuf>,
+
+    #[serde(default)]
+    pub manufacturer_id: Option<String>,
+    #[serde(default)]
+    pub model: Option<String>,
+    #[serde(default)]
+    pub serial: Option<String>,
+    #[serde(default)]
+    pub product_code: Option<u16>,
+    #[serde(default)]
+    pub serial_number: Option<u32>,
+}
+
+impl EdidOverride {
+    /// Whether this override applies to the device at the passed path and connector.
+    /// Buses may be given with or without /dev/.
+    pub fn applies_to(&self, path: &str, conn: Option<&Connector>) -> bool {
+        match (&self.bus, &self.connector) {
+            (Some(bus), _) => adapter_name(bus) == adapter_name(path),
+            (None, Some(want)) => conn.map(|c| c.is(want)).unwrap_or(false),
+            (None, None) => false,
+        }
+    }
+
+    /// Describes the device being overridden.
+    fn target(&self) -> &str {
+        self.bus
+            .as_deref()
+            .or(self.connector.as_deref())
+            .unwrap_or("unknown device")
+    }
+
+    /// Returns the overridden device info. If there's no override EDID file, the passed
+    /// EDID reader is used instead. Reader failures are ignored when explicit
+    /// fields are provided.
+    pub fn apply<F: FnOnce() -> Result<DeviceInfo>>(&self, read: F) -> Result<DeviceInfo> {
+        let mut info = match &self.edid {
+            Some(path) => DeviceInfo::parse(&std::fs::read(path)?)
+                .with_context(|| format!("failed to parse EDID override {:?}", path))?,
+            None => match read() {
+                Ok(info) => info,
+                Err(e) if self.has_fields() => {
+                    warn!(
+                        "failed to read EDID for {}, using only override values: {}",
+                        self.target(),
+                        e
+                    );
+                    DeviceInfo::default()
+                }
+                Err(e) => return Err(e),
+            },
+        };
+
+        if let Some(mfg) = &self.manufacturer_id {
+            info.manufacturer = mfg.clone();
+        }
+        if let Some(model) = &self.model {
+            info.model = model.clone();
+        }
+        if let Some(serial) = &self.serial {
+            info.serial = serial.clone();
+        }
+        if let Some(code) = self.product_code {
+            info.product_code = code;
+        }
+        if let Some(num) = self.serial_number {
+            info.serial_number = num;
+        }
+
+        Ok(info)
+    }
+
+    fn has_fields(&self) -> bool {
+        self.manufacturer_id.is_some()
+            || self.model.is_some()
+            || self.serial.is_some()
+            || self.product_code.is_some()
+            || self.serial_number.is_some()
+    }
 }
 
 #[derive(Debug, StructOpt, Default, Deserialize)]
 pub struct BrightnessOpts {
-    /// percentage of the target screen brightness during day
-    #[structopt(short, long = "day-brightness", parse(try_from_str = parse_brightness_percent))]
-    pub day_brightness: Option<u16>,
+    /// percentage of the target screen brightness during day, a luminance like
+    /// "200 nits", or a raw value like "raw 60"
+    #[structopt(short, long = "day-brightness", env = "DLUX_DAY_BRIGHTNESS")]
+    pub day_brightness: Option<Brightness>,
+
+    /// percentage of the target screen brightness after sunset, a luminance like
+    /// "80 nits", or a raw value like "raw 20"
+    #[structopt(short, long = "night-brightness", env = "DLUX_NIGHT_BRIGHTNESS")]
+    pub night_brightness: Option<Brightness>,
+
+    /// how brightness percentages are interpreted: linear, a percentage of each
+    /// display's maximum luminance, or perceptual, a percentage of perceived lightness.
+    #[structopt(long, env = "DLUX_SCALE")]
+    pub scale: Option<Scale>,
 
-    /// percentage of the target screen brightness after sunset
-    #[structopt(short, long = "night-brightness", parse(try_from_str = parse_brightness_percent))]
-    pub night_brightness: Option<u16>,
+    /// lowest brightness percentage displays may be set to, 5 unless it's lowered.
+    /// Dimmer targets are raised to it, as some displays turn off or become unreadable.
+    #[structopt(long, env = "DLUX_MIN_BRIGHTNESS")]
+    pub min_brightness: Option<u16>,
 }
 
-#[derive(StructOpt, Debug, Deserialize)]
+/// Brightness targets are raised to this percentage unless min_brightness is lowered.
+pub const DEFAULT_MIN_BRIGHTNESS: u16 = 5;
+
+#[derive(StructOpt, Debug, Default, Deserialize, Serialize)]
 pub struct GeoOpts {
-    /// latitude of your location for sunset calculations
-    #[structopt(long, alias = "lat", parse(try_from_str = parse_geo_coord))]
-    pub latitude: f64,
+    /// latitude of your location for sunset calculations, in decimal degrees or
+    /// degrees, minutes, and seconds like 40°42'46"N. Derived from the timezone if unset.
+    #[structopt(
+        long,
+        alias = "lat",
+        env = "DLUX_GEO_LATITUDE",
+        parse(try_from_str = parse_latitude)
+    )]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_latitude",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub latitude: Option<f64>,
+
+    /// longitude of your location for sunset calculations, in decimal degrees or
+    /// degrees, minutes, and seconds like 74°0'21"W. Derived from the timezone if unset.
+    #[structopt(
+        long,
+        alias = "long",
+        alias = "lng",
+        env = "DLUX_GEO_LONGITUDE",
+        parse(try_from_str = parse_longitude)
+    )]
+    #[serde(
+        default,
+        deserialize_with = "deserialize_longitude",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub longitude: Option<f64>,
+
+    /// latitude and longitude together, instead of separately: a pair like
+    /// "40.7128,-74.0060", a geohash, or a full plus code.
+    #[structopt(long, env = "DLUX_GEO_LOCATION")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
 
-    /// longitude of your location for sunset calculations
-    #[structopt(long, alias = "long", alias = "lng", parse(try_from_str = parse_geo_coord))]
-    pub longitude: f64,
+    /// city to use the coordinates of, like "Seattle" or "Portland, OR, US", from a
+    /// list of major cities built into dlux. Search it with `dlux locate`.
+    #[structopt(long, env = "DLUX_GEO_CITY")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
 
     /// altitude from sea level in meters of your location for sunset calculations
-    #[structopt(long, alias = "height", default_value = "0.0")]
+    #[structopt(
+        long,
+        alias = "height",
+        env = "DLUX_GEO_ALTITUDE",
+        default_value = "0.0"
+    )]
     #[serde(default)]
     pub altitude: f64,
+
+    /// IANA timezone, like America/New_York, whose principal city is used as your
+    /// location when latitude and longitude aren't given. Defaults to the system's.
+    #[structopt(long, env = "DLUX_GEO_TIMEZONE")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
 }
 
-fn parse_brightness_percent<T: AsRef<str>>(input: T) -> Result<u16> {
-    match input.as_ref().parse::<u16>()? {
-        0..=4 => Err(format_err!("minimum of 5% is allowed")),
-        input @ 0..=100 => Ok(input),
-        _ => Err(format_err!(
-            "option is a relative percentage and should be below 100"
-        )),
+impl GeoOpts {
+    /// The location sunrise and sunset are calculated for: the coordinates if they're
+    /// given, otherwise those of the timezone.
+    pub fn resolve(&self) -> Result<Location> {
+        let given = [
+            self.location.is_some(),
+            self.city.is_some(),
+            self.latitude.is_some() || self.longitude.is_some(),
+        ];
+        if given.iter().filter(|g| **g).count() > 1 {
+            return Err(format_err!(
+                "only one of location, city, or latitude and longitude may be given"
+            ));
+        }
+
+        let (latitude, longitude) = match (&self.location, self.latitude, self.longitude) {
+            (Some(location), _, _) => parse_location(location)?,
+            _ if self.city.is_some() => locate_city(self.city.as_deref().unwrap_or_default())?,
+            (None, Some(lat), Some(lon)) => (check_latitude(lat)?, check_longitude(lon)?),
+            (None, None, None) => {
+                let tz = match &self.timezone {
+                    Some(tz) => tz.clone(),
+                    None => system_timezone()?,
+                };
+                timezone_coordinates(&tz).context(
+                    "latitude and longitude weren't given and can't be derived from the timezone",
+                )?
+            }
+            _ => return Err(format_err!("latitude and longitude must be given together")),
+        };
+
+        let location = Location {
+            latitude,
+            longitude,
+            altitude: self.altitude,
+        };
+        location.check_sun()?;
+        Ok(location)
     }
 }
 
-fn parse_geo_coord<T: AsRef<str>>(input: T) -> Result<f64> {
-    let input = input.as_ref().parse::<f64>()?;
-    if (-180.0..=180.0).contains(&input) {
-        Err(format_err!(
-            "coordinate is out of range: -180 <= coord <= 180"
-        ))
-    } else {
-        Ok(input)
+/// a brightness target, as a percentage of the display's maximum, a luminance, or a
+/// raw value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Brightness {
+    Percent(u16),
+    /// in cd/m², which needs the display's luminance.
+    Nits(f64),
+    /// the display's own brightness value, used as is.
+    Raw(u16),
+}
+
+impl Default for Brightness {
+    fn default() -> Self {
+        Self::Percent(0)
+    }
+}
+
+impl std::str::FromStr for Brightness {
+    type Err = Error;
+
+    /// Parses a percentage like "40" or "40%", a luminance like "80 nits" or
+    /// "80 cd/m2", or a raw value like "raw 20".
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let lower = s.to_lowercase();
+        if let Some(raw) = lower.strip_prefix("raw") {
+            return raw
+                .trim()
+                .parse()
+                .map(Self::Raw)
+                .map_err(|_| format_err!("{:?} isn't a raw value like \"raw 20\"", s));
+        }
+        if let Some(nits) = ["nits", "nit", "cd/m²", "cd/m2"]
+            .iter()
+            .find_map(|unit| lower.strip_suffix(unit))
+        {
+            return match nits.trim().parse::<f64>() {
+                Ok(nits) if nits.is_finite() && nits > 0.0 => Ok(Self::Nits(nits)),
+                Ok(_) => Err(format_err!("luminance must be above 0 nits")),
+                Err(_) => Err(format_err!("{:?} isn't a luminance like \"80 nits\"", s)),
+            };
+        }
+
+        match s.strip_suffix('%').unwrap_or(s).trim().parse::<u16>() {
+            Ok(percent @ 0..=100) => Ok(Self::Percent(percent)),
+            Ok(_) => Err(format_err!(
+                "option is a relative percentage and should be below 100"
+            )),
+            Err(_) => Err(format_err!(
+                "{:?} isn't a percentage or a luminance like \"80 nits\"",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Brightness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Percent(percent) => write!(f, "{}%", percent),
+            Self::Nits(nits) => write!(f, "{} nits", nits),
+            Self::Raw(raw) => write!(f, "raw {}", raw),
+        }
+    }
+}
+
+impl Serialize for Brightness {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> std::result::Result<S::Ok, S::Error> {
+        match self {
+            Self::Percent(percent) => s.serialize_u16(*percent),
+            Self::Nits(_) | Self::Raw(_) => s.collect_str(self),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Brightness {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> std::result::Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = Brightness;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                write!(
+                    f,
+                    "a percentage, a luminance like \"80 nits\", or a raw value like \"raw 20\""
+                )
+            }
+
+            fn visit_u64<E: serde::de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(Brightness::Percent)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Unsigned(v), &self))
+            }
+
+            fn visit_i64<E: serde::de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+                u16::try_from(v)
+                    .map(Brightness::Percent)
+                    .map_err(|_| E::invalid_value(serde::de::Unexpected::Signed(v), &self))
+            }
+
+            fn visit_str<E: serde::de::Error>(
+                self,
+                v: &str,
+            ) -> std::result::Result<Self::Value, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        d.deserialize_any(Visitor)
+    }
+}
+
+/// Deserializes a coordinate given as a number or in any format the parser accepts.
+fn deserialize_coordinate<'de, D: serde::Deserializer<'de>>(
+    d: D,
+    parser: fn(&str) -> Result<f64>,
+) -> std::result::Result<Option<f64>, D::Error> {
+    struct Visitor(fn(&str) -> Result<f64>);
+
+    impl<'de> serde::de::Visitor<'de> for Visitor {
+        type Value = Option<f64>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a coordinate as a number or string")
+        }
+
+        fn visit_f64<E: serde::de::Error>(self, v: f64) -> std::result::Result<Self::Value, E> {
+            Ok(Some(v))
+        }
+
+        fn visit_i64<E: serde::de::Error>(self, v: i64) -> std::result::Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_u64<E: serde::de::Error>(self, v: u64) -> std::result::Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> std::result::Result<Self::Value, E> {
+            (self.0)(v).map(Some).map_err(E::custom)
+        }
+
+        fn visit_unit<E: serde::de::Error>(self) -> std::result::Result<Self::Value, E> {
+            Ok(None)
+        }
     }
+
+    d.deserialize_any(Visitor(parser))
+}
+
+fn deserialize_latitude<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<f64>, D::Error> {
+    deserialize_coordinate(d, parse_latitude)
+}
+
+fn deserialize_longitude<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Option<f64>, D::Error> {
+    deserialize_coordinate(d, parse_longitude)
 }
 
 /// defines a devices' matching critera and its optional brightness overrides.
 /// Absent matching values behave as wildcards, while present ones are all AND'd together.
-/// If serial is specified, it is a whole case insensitive match and overrides anything else
-/// present.
+/// Serial is a whole case insensitive match.
 ///
-/// Model and Manufacturer ID are case sensitive regular expressions. You may include flags to
-/// toggle case sensitivity [as outlined in the regex crate](https://docs.rs/regex/1.4.2/regex/#grouping-and-flags),
+/// Model, Manufacturer ID, and serial_regex are case sensitive regular expressions. You may
+/// include flags to toggle case sensitivity [as outlined in the regex crate](https://docs.rs/regex/1.4.2/regex/#grouping-and-flags),
 /// for example "(?i)&dell U2720Q" is case insensitive.
+///
+/// Matchers may be combined with `not`, `any`, and `all`, or refer to a matcher in
+/// the top-level aliases with `alias`.
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DeviceOpts {
-    #[serde(default, with = "serde_regex", skip_serializing_if = "Option::is_none")]
-    pub model: Option<Regex>,
-    #[serde(default, with = "serde_regex", skip_serializing_if = "Option::is_none")]
-    pub manufacturer_id: Option<Regex>,
-    // XXX: override exclusivity.
-    pub serial: Option<String>,
+    #[serde(flatten)]
+    pub matcher: DeviceMatcher,
 
     /// Forces a specific day brightness for matching devices,
     /// overriding global configuration.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub day_brightness: Option<u16>,
+    pub day_brightness: Option<Brightness>,
     /// Forces a specific night brightness for matching devices,
     /// overriding global configuration.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub night_brightness: Option<u16>,
+    pub night_brightness: Option<Brightness>,
+    /// Forces a specific brightness scale for matching devices,
+    /// overriding global configuration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub scale: Option<Scale>,
+    /// Forces a specific minimum brightness percentage for matching devices,
+    /// overriding global configuration.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_brightness: Option<u16>,
+    /// Lowest raw brightness value matching devices are set to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_raw: Option<u16>,
+    /// Highest raw brightness value matching devices are set to.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_raw: Option<u16>,
+    /// How the luminance of matching devices responds to their raw brightness.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub calibration: Option<CalibrationOpts>,
+    /// The luminance of matching devices, for luminance targets. Defaults to the
+    /// maximum luminance in their EDID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub luminance: Option<LuminanceOpts>,
+}
+
+/// a device's luminance in cd/m², either its range or measured at raw values.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LuminanceOpts {
+    /// luminance at the lowest raw value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_nits: Option<f64>,
+    /// luminance at the highest raw value.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_nits: Option<f64>,
+    /// pairs of raw value and the luminance measured at it, interpolated between.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<(u16, f64)>,
+}
+
+impl LuminanceOpts {
+    fn luminance(self) -> Result<Luminance> {
+        match (self.min_nits, self.max_nits, self.points.is_empty()) {
+            (min_nits, Some(max_nits), true) => {
+                let min_nits = min_nits.unwrap_or_default();
+                if !(0.0 <= min_nits && min_nits < max_nits && max_nits.is_finite()) {
+                    return Err(format_err!(
+                        "luminance must have 0 <= min_nits < max_nits, but has {} and {}",
+                        min_nits,
+                        max_nits
+                    ));
+                }
+                Ok(Luminance::Range { min_nits, max_nits })
+            }
+            (None, None, false) => {
+                if self.points.len() < 2 {
+                    return Err(format_err!("luminance needs at least 2 points"));
+                }
+                if self.points.iter().any(|(_, n)| !n.is_finite() || *n < 0.0) {
+                    return Err(format_err!("luminance points can't be below 0 nits"));
+                }
+                if self
+                    .points
+                    .windows(2)
+                    .any(|w| w[0].0 >= w[1].0 || w[0].1 >= w[1].1)
+                {
+                    return Err(format_err!(
+                        "luminance points must increase in both raw value and nits"
+                    ));
+                }
+                Ok(Luminance::Points {
+                    points: self.points,
+                })
+            }
+            (_, _, false) => Err(format_err!(
+                "luminance may have a range or points, but not both"
+            )),
+            (_, None, true) => Err(format_err!("luminance needs max_nits or points")),
+        }
+    }
+}
+
+/// a device's measured response to its raw brightness, as either a gamma exponent or
+/// raw values for brightness percentages.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CalibrationOpts {
+    /// luminance is the raw value's fraction of the maximum raised to this power.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gamma: Option<f64>,
+    /// pairs of brightness percentage and raw value, interpolated between.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub points: Vec<(f64, u16)>,
+}
+
+impl CalibrationOpts {
+    fn response(self) -> Result<Response> {
+        match (self.gamma, self.points.is_empty()) {
+            (Some(gamma), true) if gamma.is_finite() && gamma > 0.0 => {
+                Ok(Response::Gamma { gamma })
+            }
+            (Some(gamma), true) => Err(format_err!(
+                "calibration gamma {} must be greater than 0",
+                gamma
+            )),
+            (None, false) => {
+                if self.points.len() < 2 {
+                    return Err(format_err!("calibration needs at least 2 points"));
+                }
+                if let Some((p, _)) = self.points.iter().find(|(p, _)| !(0.0..=100.0).contains(p)) {
+                    return Err(format_err!(
+                        "calibration point for {}% is out of range: 0 <= percentage <= 100",
+                        p
+                    ));
+                }
+                if self
+                    .points
+                    .windows(2)
+                    .any(|w| w[0].0 >= w[1].0 || w[0].1 >= w[1].1)
+                {
+                    return Err(format_err!(
+                        "calibration points must increase in both percentage and raw value"
+                    ));
+                }
+                Ok(Response::Points {
+                    points: self.points,
+                })
+            }
+            (Some(_), false) => Err(format_err!(
+                "calibration may have a gamma or points, but not both"
+            )),
+            (None, true) => Err(format_err!("calibration needs a gamma or points")),
+        }
+    }
 }
 
 impl DeviceOpts {
@@ -113,9 +1657,12 @@ impl DeviceOpts {
         serial: Option<String>,
     ) -> DeviceOpts {
         Self {
-            model,
-            manufacturer_id,
-            serial,
+            matcher: DeviceMatcher {
+                model,
+                mfg: manufacturer_id,
+                serial,
+                ..DeviceMatcher::default()
+            },
             ..Self::default()
         }
     }
@@ -129,19 +1676,90 @@ pub struct DeviceConfig {
 
     /// Forces a specific day brightness for matching devices,
     /// overriding global configuration.
-    pub day_brightness: f64,
+    pub day_brightness: Brightness,
     /// Forces a specific night brightness for matching devices,
     /// overriding global configuration.
-    pub night_brightness: f64,
+    pub night_brightness: Brightness,
+    /// Converts brightness to matching devices' raw values.
+    pub curve: BrightnessCurve,
+    /// The luminance of matching devices, if it's configured.
+    pub luminance: Option<Luminance>,
+    /// Lowest brightness percentage matching devices are set to.
+    pub min_brightness: u16,
+
+    /// Whether the rule was added to manage displays no other rule matches.
+    pub fallback: bool,
 }
 
 impl DeviceConfig {
-    fn try_from_opts(opts: DeviceOpts, defaults: &BrightnessOpts) -> Result<DeviceConfig> {
-        let matcher = DeviceMatcher {
-            model: opts.model,
-            mfg: opts.manufacturer_id,
-            serial: opts.serial,
+    /// The brightness for the time of day of a display the rule claims, relative to
+    /// its maximum along the curve. Luminance and raw targets are kept at or above the
+    /// minimum brightness, as they aren't known to be until the display is.
+    pub fn brightness_for(&self, is_daytime: bool, info: &DeviceInfo, max: u16) -> Result<f64> {
+        let target = if is_daytime {
+            self.day_brightness
+        } else {
+            self.night_brightness
+        };
+        let brightness = match target {
+            Brightness::Percent(percent) => return Ok(percent as f64 / 100.0),
+            Brightness::Nits(nits) => self.luminance_brightness(nits, info, max)?,
+            Brightness::Raw(raw) => {
+                if raw > max {
+                    warn!(
+                        "{} has a maximum raw brightness of {}, so it can't reach raw {}",
+                        info, max, raw
+                    );
+                }
+                self.curve.brightness(raw.min(max), max)
+            }
         };
+
+        let min = self.min_brightness as f64 / 100.0;
+        if brightness < min {
+            warn!(
+                "{} for {} is below the minimum brightness of {}%, using that instead",
+                target, info, self.min_brightness
+            );
+            return Ok(min);
+        }
+        Ok(brightness)
+    }
+
+    /// The brightness at which a display is as bright as the luminance, using the
+    /// rule's luminance or, failing that, the maximum luminance in the display's EDID.
+    fn luminance_brightness(&self, nits: f64, info: &DeviceInfo, max: u16) -> Result<f64> {
+        let luminance = match (&self.luminance, info.max_luminance) {
+            (Some(luminance), _) => luminance.clone(),
+            (None, Some(max_nits)) => Luminance::Range {
+                min_nits: 0.0,
+                max_nits,
+            },
+            (None, None) => {
+                return Err(format_err!(
+                    "{} nits needs the display's luminance, which isn't configured or in its EDID",
+                    nits
+                ))
+            }
+        };
+        let (min_nits, max_nits) = luminance.range();
+        if nits < min_nits || nits > max_nits {
+            warn!(
+                "{} ranges from {} to {} nits, so it can't reach {} nits",
+                info, min_nits, max_nits, nits
+            );
+        }
+        Ok(luminance.brightness(nits, &self.curve, max))
+    }
+
+    fn try_from_opts(
+        opts: DeviceOpts,
+        defaults: &BrightnessOpts,
+        aliases: &HashMap<String, DeviceMatcher>,
+        warnings: &mut Vec<String>,
+    ) -> Result<DeviceConfig> {
+        let mut matcher = opts.matcher;
+        matcher.resolve_aliases(aliases)?;
         trace!("parsed matcher: {}", matcher);
 
         let day_brightness = opts.day_brightness.or(defaults.day_brightness).ok_or_else(
@@ -151,63 +1769,409 @@ impl DeviceConfig {
             || format_err!("night brightness was absent for rule that {}; it must be provided top-level or in all devices", matcher)
         )?;
 
+        let luminance = opts.luminance.map(LuminanceOpts::luminance).transpose()?;
+        if let (Some(min), Some(max)) = (opts.min_raw, opts.max_raw) {
+            if min > max {
+                return Err(format_err!("min_raw {} is above max_raw {}", min, max));
+            }
+        }
+        let curve = BrightnessCurve {
+            scale: opts.scale.or(defaults.scale).unwrap_or_default(),
+            response: opts
+                .calibration
+                .map(CalibrationOpts::response)
+                .transpose()?
+                .unwrap_or_default(),
+            min_raw: opts.min_raw,
+            max_raw: opts.max_raw,
+        };
+        if curve.scale == Scale::Perceptual && matches!(curve.response, Response::Points { .. }) {
+            return Err(format_err!(
+                "calibration points map percentages straight to raw values, so they can't be used with scale: perceptual; set scale: linear for the rule"
+            ));
+        }
+
+        let min_brightness = check_min_brightness(opts.min_brightness.or(defaults.min_brightness))?;
+        let day_brightness = check_target("day", day_brightness, min_brightness, &curve, warnings)?;
+        let night_brightness =
+            check_target("night", night_brightness, min_brightness, &curve, warnings)?;
+
         Ok(DeviceConfig {
             matcher,
-            day_brightness: day_brightness as f64 / 100.0,
-            night_brightness: night_brightness as f64 / 100.0,
+            day_brightness,
+            night_brightness,
+            curve,
+            luminance,
+            min_brightness,
+            fallback: false,
+        })
+    }
+}
+
+/// The minimum brightness, or the default one if it's unset.
+fn check_min_brightness(min_brightness: Option<u16>) -> Result<u16> {
+    match min_brightness.unwrap_or(DEFAULT_MIN_BRIGHTNESS) {
+        min @ 0..=100 => Ok(min),
+        min => Err(format_err!(
+            "min_brightness {} is a percentage and should be below 100",
+            min
+        )),
+    }
+}
+
+/// Checks a target is between the raw clamps, raising percentages below the minimum
+/// brightness to it with a warning. Raw and luminance targets are raised once the
+/// display's range is known.
+fn check_target(
+    which: &str,
+    target: Brightness,
+    min_brightness: u16,
+    curve: &BrightnessCurve,
+    warnings: &mut Vec<String>,
+) -> Result<Brightness> {
+    match target {
+        Brightness::Percent(percent) if percent < min_brightness => {
+            warnings.push(format!(
+                "{} brightness of {}% is below the minimum of {}%, so that's used instead; lower min_brightness to allow it",
+                which, percent, min_brightness
+            ));
+            Ok(Brightness::Percent(min_brightness))
+        }
+        Brightness::Raw(raw)
+            if curve.min_raw.is_some_and(|min| raw < min)
+                || curve.max_raw.is_some_and(|max| raw > max) =>
+        {
+            Err(format_err!(
+                "{} brightness of raw {} is outside of min_raw and max_raw",
+                which,
+                raw
+            ))
+        }
+        _ => Ok(target),
+    }
+}
+
+/// Builds the rules from device options, followed by a wildcard rule unless matching
+/// is exclusive. Problems are prefixed and added to problems, along with the path to
+/// the options they're about, which are under the keys.
+#[allow(clippy::too_many_arguments)]
+fn build_rules(
+    devices: Vec<DeviceOpts>,
+    brightness: &BrightnessOpts,
+    device_match_exclusive: bool,
+    aliases: &HashMap<String, DeviceMatcher>,
+    prefix: &str,
+    at: &[Key],
+    problems: &mut Vec<(Vec<Key>, String)>,
+    warnings: &mut Vec<String>,
+) -> Vec<DeviceConfig> {
+    let under = |names: &[&str]| [at, &keys(names)].concat();
+    let rules = devices.len();
+    let mut devices = devices
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, opts)| {
+            let mut found = vec![];
+            let cfg = DeviceConfig::try_from_opts(opts, brightness, aliases, &mut found);
+            warnings.extend(
+                found
+                    .into_iter()
+                    .map(|w| format!("{}rule {}: {}", prefix, i + 1, w)),
+            );
+            match cfg {
+                Ok(cfg) => Some(cfg),
+                Err(e) => {
+                    let mut rule = under(&["devices"]);
+                    rule.push(Key::Index(i));
+                    problems.push((rule, format!("{}rule {}: {}", prefix, i + 1, e)));
+                    None
+                }
+            }
         })
+        .collect::<Vec<_>>();
+
+    // Fudge a wildcard matcher if there are no devices or if exclusive_match
+    // is false. Configs from before device_match_exclusive was honored may leave it
+    // unset and rely on unmatched displays being left alone, which is kept for now.
+    let unset = brightness.day_brightness.is_none() && brightness.night_brightness.is_none();
+    if !device_match_exclusive && rules != 0 && unset {
+        warnings.push(format!(
+            "{}displays no rule matches are left alone, as there's no top-level day_brightness or night_brightness; set device_match_exclusive: true to keep that, as a later release will require the brightness otherwise",
+            prefix
+        ));
+    } else if !device_match_exclusive || rules == 0 {
+        if rules != 0 {
+            warnings.push(format!(
+                "{}unmatched displays are now set to the top-level brightness; set device_match_exclusive: true to keep the old behaviour of leaving them alone",
+                prefix
+            ));
+        }
+        let why = if rules == 0 {
+            "there are no devices"
+        } else {
+            "device_match_exclusive isn't set"
+        };
+        if brightness.day_brightness.is_none() {
+            problems.push((at.to_vec(), format!(
+                "{}must specify --day-brightness for target daytime brightness percentage, as {} and unmatched displays use it",
+                prefix, why
+            )));
+        }
+        if brightness.night_brightness.is_none() {
+            problems.push((at.to_vec(), format!(
+                "{}must specify --night-brightness for target nighttime brightness percentage, as {} and unmatched displays use it",
+                prefix, why
+            )));
+        }
+        if let (Some(day), Some(night)) = (brightness.day_brightness, brightness.night_brightness) {
+            let curve = BrightnessCurve {
+                scale: brightness.scale.unwrap_or_default(),
+                ..BrightnessCurve::default()
+            };
+            let mut found = vec![];
+            let mut option = at.to_vec();
+            let checked = check_min_brightness(brightness.min_brightness).and_then(|min| {
+                option = under(&["day_brightness"]);
+                let day = check_target("day", day, min, &curve, &mut found)?;
+                option = under(&["night_brightness"]);
+                let night = check_target("night", night, min, &curve, &mut found)?;
+                Ok((min, day, night))
+            });
+            warnings.extend(found.into_iter().map(|w| format!("{}{}", prefix, w)));
+            match checked {
+                // at the end so it matches at lowest priority
+                Ok((min_brightness, day, night)) => devices.push(DeviceConfig {
+                    matcher: DeviceMatcher::default(),
+                    day_brightness: day,
+                    night_brightness: night,
+                    curve,
+                    luminance: None,
+                    min_brightness,
+                    fallback: true,
+                }),
+                Err(e) => problems.push((option, format!("{}{}", prefix, e))),
+            }
+        }
     }
+
+    devices
 }
 
 // Normalized output for both config and CLI options.
 #[derive(Debug)]
 pub struct Config {
-    pub geo: GeoOpts,
+    pub geo: Location,
     pub devices: Vec<DeviceConfig>,
+    pub discovery: DiscoveryOpts,
     pub logging: LogOpts,
+    pub state_file: Option<std::path::PathBuf>,
+    pub restore_brightness_on_exit: bool,
+    pub manual_changes: Option<ManualChangeOpts>,
+    pub gpsd: Option<GpsdOpts>,
+    /// rules of each named profile other than the default.
+    pub profiles: BTreeMap<String, Vec<DeviceConfig>>,
+    /// profile used when the daemon first starts.
+    pub profile: String,
+    pub control_socket: Option<std::path::PathBuf>,
+    pub hooks: Vec<HookOpts>,
+    pub max_concurrent_hooks: usize,
+    /// problems which don't stop the config being used. They're found before logging
+    /// is set up, so they're left for whoever loaded the config to log.
+    pub warnings: Vec<String>,
 }
 
 impl Config {
-    pub fn new<T: IntoIterator<Item = DeviceOpts>>(
-        geo: GeoOpts,
-        logging: LogOpts,
-        brightness: BrightnessOpts,
-        devices: T,
-        exclusive_match: bool,
-    ) -> Result<Self> {
-        let mut devices = devices
+    /// Validates and normalizes the options. Every problem found is reported, one per
+    /// line of the error.
+    pub fn new(opts: Opts) -> Result<Self> {
+        let Opts {
+            geo,
+            brightness,
+            logging,
+            device_match_exclusive,
+            devices,
+            aliases,
+            mut discovery,
+            state_file,
+            restore_brightness_on_exit,
+            manual_changes,
+            gpsd,
+            profiles,
+            profile,
+            control_socket,
+            hooks,
+            max_concurrent_hooks,
+            mut warnings,
+            sources,
+        } = opts;
+        // each with the path to the options it's about
+        let mut problems = vec![];
+        let item = |names: &[&str], i: usize| {
+            let mut path = keys(names);
+            path.push(Key::Index(i));
+            path
+        };
+
+        for (i, o) in discovery.edid_overrides.iter().enumerate() {
+            if o.bus.is_none() && o.connector.is_none() {
+                problems.push((
+                    item(&["edid_overrides"], i),
+                    format!(
+                        "EDID override {} ({:?}) must have a bus or connector",
+                        i + 1,
+                        o
+                    ),
+                ));
+            }
+        }
+
+        for (i, quirk) in discovery.ddc.quirks.iter_mut().enumerate() {
+            if let Err(e) = quirk.matcher.resolve_aliases(&aliases) {
+                problems.push((
+                    item(&["ddc", "quirks"], i),
+                    format!("DDC quirk {}: {}", i + 1, e),
+                ));
+            }
+        }
+
+        let devices = build_rules(
+            devices,
+            &brightness,
+            device_match_exclusive,
+            &aliases,
+            "",
+            &[],
+            &mut problems,
+            &mut warnings,
+        );
+
+        let profiles = profiles
             .into_iter()
-            .map(|opts| DeviceConfig::try_from_opts(opts, &brightness))
-            .collect::<Result<Vec<_>>>()?;
-
-        // Fudge a wildcard matcher if there are no devices or if exclusive_match
-        // is false.
-        if exclusive_match || devices.is_empty() {
-            // at the end so it matches at lowest priority
-            devices.push(DeviceConfig {
-                day_brightness: brightness.day_brightness.ok_or_else(|| {
-                    format_err!(
-                        "must specify --day-brightness for target daytime brightness percentage"
-                    )
-                })? as f64
-                    / 100.0,
-                night_brightness: brightness.night_brightness.ok_or_else(|| {
-                    format_err!(
-                    "must specify --night-brightness for target nighttime brightness percentage"
-                )
-                })? as f64
-                    / 100.0,
-                matcher: DeviceMatcher::default(),
-            });
+            .filter_map(|(name, p)| {
+                let at = keys(&["profiles", &name]);
+                if name == DEFAULT_PROFILE {
+                    problems.push((
+                        at,
+                        format!("profile {:?} is reserved for the top-level devices", name),
+                    ));
+                    return None;
+                }
+                // requests on the control socket are split on whitespace.
+                if name.is_empty() || name.contains(char::is_whitespace) {
+                    problems.push((at, format!(
+                        "profile {:?} can't be switched to; names must be non-empty without whitespace",
+                        name
+                    )));
+                    return None;
+                }
+
+                let brightness = BrightnessOpts {
+                    day_brightness: p.brightness.day_brightness.or(brightness.day_brightness),
+                    night_brightness: p
+                        .brightness
+                        .night_brightness
+                        .or(brightness.night_brightness),
+                    scale: p.brightness.scale.or(brightness.scale),
+                    min_brightness: p.brightness.min_brightness.or(brightness.min_brightness),
+                };
+                let rules = build_rules(
+                    p.devices,
+                    &brightness,
+                    p.device_match_exclusive.unwrap_or(device_match_exclusive),
+                    &aliases,
+                    &format!("profile {}: ", name),
+                    &at,
+                    &mut problems,
+                    &mut warnings,
+                );
+                Some((name, rules))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let profile = profile.unwrap_or_else(|| DEFAULT_PROFILE.to_owned());
+        if profile != DEFAULT_PROFILE && !profiles.contains_key(&profile) {
+            problems.push((
+                keys(&["profile"]),
+                format!("profile {:?} is not defined", profile),
+            ));
+        }
+
+        for (i, hook) in hooks.iter().enumerate() {
+            for event in hook.events.iter() {
+                if !crate::hooks::EVENTS.contains(&event.as_str()) {
+                    problems.push((
+                        item(&["hooks"], i),
+                        format!(
+                            "hook {}: unknown event {:?}, expected one of {}",
+                            i + 1,
+                            event,
+                            crate::hooks::EVENTS.join(", ")
+                        ),
+                    ));
+                }
+            }
+        }
+        if max_concurrent_hooks == Some(0) {
+            problems.push((
+                keys(&["max_concurrent_hooks"]),
+                "max_concurrent_hooks must be at least 1".to_owned(),
+            ));
+        }
+
+        let geo = geo.resolve().unwrap_or_else(|e| {
+            problems.push((keys(&["geo"]), format!("geo: {:#}", e)));
+            Location::default()
+        });
+
+        if !problems.is_empty() {
+            let problems =
+                problems
+                    .into_iter()
+                    .map(|(path, problem)| match sources.locate(&path) {
+                        Some(at) => format!("{}: {}", at, problem),
+                        None => problem,
+                    });
+            return Err(format_err!("{}", problems.collect::<Vec<_>>().join("\n")));
         }
 
         Ok(Config {
             devices,
+            discovery,
             geo,
             logging,
+            state_file,
+            restore_brightness_on_exit,
+            manual_changes,
+            gpsd,
+            profiles,
+            profile,
+            control_socket,
+            hooks,
+            max_concurrent_hooks: max_concurrent_hooks.unwrap_or(DEFAULT_MAX_CONCURRENT_HOOKS),
+            warnings,
         })
     }
 
+    /// The rules of the named profile, if it's defined.
+    pub fn rules(&self, profile: &str) -> Option<&[DeviceConfig]> {
+        if profile == DEFAULT_PROFILE {
+            return Some(&self.devices);
+        }
+        self.profiles.get(profile).map(Vec::as_slice)
+    }
+
+    /// Names of every profile, starting with the default.
+    pub fn profile_names(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(DEFAULT_PROFILE).chain(self.profiles.keys().map(String::as_str))
+    }
+
+    /// Reads the config from a file in the format, or detected from its extension
+    /// if None.
+    pub fn from_file(path: &std::path::Path, format: Option<Format>) -> Result<Self> {
+        Config::new(Opts::from_file(path, format)?)
+            .with_context(|| format!("invalid configuration in {:?}", path))
+    }
+
     pub fn from_args() -> Result<Self> {
         Opts::from_args().try_into()
     }
@@ -217,15 +2181,7 @@ impl TryFrom<std::path::PathBuf> for Config {
     type Error = anyhow::Error;
 
     fn try_from(path: std::path::PathBuf) -> Result<Self> {
-        let opts: Opts = serde_yaml::from_reader(std::fs::File::open(path)?)?;
-
-        Config::new(
-            opts.geo,
-            opts.logging,
-            opts.brightness,
-            opts.devices,
-            opts.device_match_exclusive,
-        )
+        Config::from_file(&path, None)
     }
 }
 
@@ -233,6 +2189,694 @@ impl TryFrom<Opts> for Config {
     type Error = Error;
 
     fn try_from(opts: Opts) -> Result<Self> {
-        Config::new(opts.geo, opts.logging, opts.brightness, opts.devices, true)
+        Config::new(opts)
+    }
+}
+
+#[test]
+fn test_ddc_quirks() {
+    let opts: DdcOpts = serde_yaml::from_str(
+        "
+command_delay_ms: 80
+quirks:
+  - model: U2415
+    reply_delay_ms: 150
+",
+    )
+    .unwrap();
+    let mut info = DeviceInfo {
+        model: "DELL P2415Q".to_owned(),
+        ..DeviceInfo::default()
+    };
+
+    assert_eq!(
+        DdcTiming {
+            command_delay_ms: 80,
+            reply_delay_ms: 50
+        },
+        opts.timing_for(&info)
+    );
+
+    info.model = "DELL U2415".to_owned();
+    assert_eq!(
+        DdcTiming {
+            command_delay_ms: 80,
+            reply_delay_ms: 150
+        },
+        opts.timing_for(&info)
+    );
+}
+
+/// Loads a config located at 20, -100 with the extra options, for tests.
+#[cfg(test)]
+fn parse_config(extra: &str) -> std::result::Result<Config, String> {
+    let yaml = format!("geo: {{latitude: 20, longitude: -100}}\n{}", extra);
+    Config::new(serde_yaml::from_str::<Opts>(&yaml).unwrap()).map_err(|e| e.to_string())
+}
+
+#[test]
+fn test_fallback_rule() {
+    let cfg = parse_config(
+        "day_brightness: 100\nnight_brightness: 40\ndevices: [{model: U2415, night_brightness: 20}]",
+    )
+    .unwrap();
+    assert_eq!(2, cfg.devices.len());
+    assert!(cfg.devices[1].fallback);
+    // which earlier releases left alone
+    assert!(
+        cfg.warnings[0].contains("unmatched displays are now set"),
+        "{:?}",
+        cfg.warnings
+    );
+
+    let cfg = parse_config(
+        "device_match_exclusive: true\ndevices: [{model: U2415, day_brightness: 80, night_brightness: 20}]",
+    )
+    .unwrap();
+    assert_eq!(1, cfg.devices.len());
+    assert!(!cfg.devices[0].fallback);
+
+    // every problem is reported
+    let err = parse_config("devices: [{alias: nope}, {model: U2415}]").unwrap_err();
+    assert_eq!(2, err.lines().count(), "{}", err);
+
+    // unmatched displays are still left alone by older configs which rely on it
+    let rule = "devices: [{model: U2415, day_brightness: 80, night_brightness: 20}]";
+    let cfg = parse_config(rule).unwrap();
+    assert_eq!(1, cfg.devices.len());
+    assert_eq!(1, cfg.warnings.len());
+    let err = parse_config(&format!("day_brightness: 100\n{}", rule)).unwrap_err();
+    assert!(err.contains("device_match_exclusive isn't set"), "{}", err);
+}
+
+#[test]
+fn test_calibration() {
+    let parse = |devices: &str| {
+        parse_config(&format!(
+            "day_brightness: 100\nnight_brightness: 40\nscale: perceptual\ndevices: {}",
+            devices
+        ))
+    };
+
+    let cfg = parse("[{model: U2415, calibration: {gamma: 2.2}}, {model: P2415Q, scale: linear, calibration: {points: [[0, 0], [50, 10], [100, 100]]}}]").unwrap();
+    assert_eq!(
+        BrightnessCurve {
+            scale: Scale::Perceptual,
+            response: Response::Gamma { gamma: 2.2 },
+            ..BrightnessCurve::default()
+        },
+        cfg.devices[0].curve
+    );
+    assert_eq!(Scale::Linear, cfg.devices[1].curve.scale);
+    assert_eq!(
+        Response::Points {
+            points: vec![(0.0, 0), (50.0, 10), (100.0, 100)]
+        },
+        cfg.devices[1].curve.response
+    );
+    assert_eq!(Scale::Perceptual, cfg.devices[2].curve.scale);
+
+    for bad in [
+        "{gamma: 0}",
+        "{gamma: 2.2, points: [[0, 0], [100, 100]]}",
+        "{points: [[0, 0]]}",
+        "{points: [[0, 0], [120, 100]]}",
+        "{points: [[0, 10], [100, 5]]}",
+        "{}",
+    ] {
+        let err = parse(&format!("[{{model: U2415, calibration: {}}}]", bad)).unwrap_err();
+        assert!(err.contains("rule 1: calibration"), "{}", err);
+    }
+
+    // the top-level scale would be ignored by the points
+    let err = parse("[{model: U2415, calibration: {points: [[0, 0], [100, 100]]}}]").unwrap_err();
+    assert!(err.contains("scale: perceptual"), "{}", err);
+}
+
+#[test]
+fn test_luminance_targets() {
+    let parse = |yaml: &str| {
+        parse_config(&format!(
+            "day_brightness: 100%\nnight_brightness: 80 nits\n{}",
+            yaml
+        ))
+    };
+
+    let cfg = parse("devices: [{model: U2415, luminance: {min_nits: 20, max_nits: 320}}, {model: P2415Q, day_brightness: 250 cd/m2, luminance: {points: [[0, 40], [50, 150], [100, 300]]}}]").unwrap();
+    let info = DeviceInfo::default();
+    assert_eq!(Brightness::Nits(80.0), cfg.devices[0].night_brightness);
+    assert_eq!(
+        Ok(0.2),
+        cfg.devices[0]
+            .brightness_for(false, &info, 100)
+            .map_err(|e| e.to_string())
+    );
+    assert_eq!(
+        Ok(1.0),
+        cfg.devices[0]
+            .brightness_for(true, &info, 100)
+            .map_err(|e| e.to_string())
+    );
+    let night = cfg.devices[1].brightness_for(false, &info, 100).unwrap();
+    assert!((night - 0.18).abs() < 0.01, "{}", night);
+
+    // the fallback rule relies on the EDID
+    let fallback = &cfg.devices[2];
+    assert!(fallback.brightness_for(false, &info, 100).is_err());
+    let info = DeviceInfo {
+        max_luminance: Some(400.0),
+        ..DeviceInfo::default()
+    };
+    assert_eq!(0.2, fallback.brightness_for(false, &info, 100).unwrap());
+
+    for bad in [
+        "{min_nits: 300, max_nits: 200}",
+        "{max_nits: 300, points: [[0, 40], [100, 300]]}",
+        "{points: [[0, 300], [100, 40]]}",
+        "{min_nits: 20}",
+    ] {
+        let err = parse(&format!("devices: [{{model: U2415, luminance: {}}}]", bad)).unwrap_err();
+        assert!(err.contains("rule 1: luminance"), "{}", err);
+    }
+    assert!(serde_yaml::from_str::<Opts>("night_brightness: 0 nits").is_err());
+    assert!(serde_yaml::from_str::<Opts>("night_brightness: bright").is_err());
+    assert_eq!(Brightness::Nits(80.0), "80nits".parse().unwrap());
+}
+
+#[test]
+fn test_min_brightness() {
+    let parse = |yaml: &str| parse_config(&format!("day_brightness: 80\n{}", yaml));
+
+    // raised to the minimum, like raw and luminance targets
+    let cfg = parse("night_brightness: 2").unwrap();
+    assert_eq!(Brightness::Percent(5), cfg.devices[0].night_brightness);
+    assert!(
+        cfg.warnings[0].contains("night brightness of 2% is below the minimum of 5%"),
+        "{:?}",
+        cfg.warnings
+    );
+    let cfg = parse("night_brightness: 2\nmin_brightness: 1").unwrap();
+    assert_eq!(Brightness::Percent(2), cfg.devices[0].night_brightness);
+    assert!(cfg.warnings.is_empty());
+    assert!(parse("night_brightness: 10\nmin_brightness: 101").is_err());
+
+    // lowered for a single display
+    let cfg = parse("night_brightness: 20\ndevices: [{model: OLED, night_brightness: 1, min_brightness: 0, min_raw: 1, max_raw: 90}, {model: U2415, night_brightness: raw 3}]").unwrap();
+    assert_eq!(Brightness::Percent(1), cfg.devices[0].night_brightness);
+    assert_eq!(
+        (Some(1), Some(90)),
+        (cfg.devices[0].curve.min_raw, cfg.devices[0].curve.max_raw)
+    );
+    assert_eq!(Brightness::Raw(3), cfg.devices[1].night_brightness);
+    let info = DeviceInfo::default();
+    // raw targets are held to the minimum once the display's range is known
+    assert_eq!(
+        0.05,
+        cfg.devices[1].brightness_for(false, &info, 100).unwrap()
+    );
+    assert_eq!(
+        0.3,
+        cfg.devices[1].brightness_for(false, &info, 10).unwrap()
+    );
+
+    let err = parse(
+        "night_brightness: 20\ndevices: [{model: U2415, night_brightness: raw 95, max_raw: 90}]",
+    )
+    .unwrap_err();
+    assert!(err.contains("outside of min_raw and max_raw"), "{}", err);
+    let err = parse("night_brightness: 20\ndevices: [{model: U2415, min_raw: 50, max_raw: 40}]")
+        .unwrap_err();
+    assert!(err.contains("min_raw 50 is above max_raw 40"), "{}", err);
+}
+
+#[test]
+fn test_hooks_config() {
+    let parse = |yaml: &str| {
+        parse_config(&format!(
+            "day_brightness: 80\nnight_brightness: 20\n{}",
+            yaml
+        ))
+    };
+
+    let cfg = parse("hooks: [{command: notify-send dlux, events: [sunset, ddc_failure]}]").unwrap();
+    assert_eq!(DEFAULT_MAX_CONCURRENT_HOOKS, cfg.max_concurrent_hooks);
+    assert_eq!(30, cfg.hooks[0].timeout_secs);
+
+    let err =
+        parse("hooks: [{command: 'true', events: [dusk]}]\nmax_concurrent_hooks: 0").unwrap_err();
+    assert!(err.contains("hook 1: unknown event \"dusk\""), "{}", err);
+    assert!(
+        err.contains("max_concurrent_hooks must be at least 1"),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn test_profiles() {
+    let parse = |yaml: &str| {
+        parse_config(&format!(
+            "day_brightness: 100\nnight_brightness: 40\n{}",
+            yaml
+        ))
+    };
+
+    let cfg = parse(
+        "profile: presentation\nprofiles:\n  presentation: {night_brightness: 90}\n  movie:\n    devices: [{model: U2415, day_brightness: 30}]",
+    )
+    .unwrap();
+    assert_eq!("presentation", cfg.profile);
+    assert_eq!(
+        vec!["default", "movie", "presentation"],
+        cfg.profile_names().collect::<Vec<_>>()
+    );
+    let rules = cfg.rules("presentation").unwrap();
+    assert_eq!(
+        (Brightness::Percent(100), Brightness::Percent(90)),
+        (rules[0].day_brightness, rules[0].night_brightness)
+    );
+    let rules = cfg.rules("movie").unwrap();
+    assert_eq!(2, rules.len());
+    assert_eq!(
+        (Brightness::Percent(30), Brightness::Percent(40)),
+        (rules[0].day_brightness, rules[0].night_brightness)
+    );
+    assert!(cfg.rules("nope").is_none());
+
+    let err = parse("profile: nope\nprofiles: {default: {}}").unwrap_err();
+    assert_eq!(2, err.lines().count(), "{}", err);
+
+    let err = parse("profiles: {\"movie night\": {}}").unwrap_err();
+    assert!(err.contains("movie night"), "{}", err);
+}
+
+#[test]
+fn test_env_overrides() {
+    let vars = |vars: &[(&str, &str)]| {
+        vars.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Vec<_>>()
+    };
+    let all = [ENV_FLAG_OPTIONS, ENV_OPTIONS].concat();
+    let mut warnings = vec![];
+
+    let env = env_overrides(
+        vars(&[
+            ("DLUX_GEO_LATITUDE", "45.5"),
+            ("DLUX_NIGHT_BRIGHTNESS", "30"),
+            ("DLUX_DEVICES", "[{model: U2415, day_brightness: 70}]"),
+            ("DLUX_PROFILE", ""),
+            ("DLUX_CONFIG", "/etc/dlux/config.yaml"),
+            ("HOME", "/root"),
+        ]),
+        &all,
+        &mut warnings,
+    )
+    .unwrap();
+    assert_eq!(
+        vec!["DLUX_DEVICES", "DLUX_GEO_LATITUDE", "DLUX_NIGHT_BRIGHTNESS"],
+        env.iter().map(|(n, _, _)| n.as_str()).collect::<Vec<_>>()
+    );
+
+    let mut base: serde_yaml::Value = serde_yaml::from_str(
+        "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\ndevices: [{model: P2415Q}]",
+    )
+    .unwrap();
+    for (_, keys, value) in env {
+        set_key(&mut base, keys, value);
+    }
+    let opts: Opts = serde_yaml::from_value(base).unwrap();
+    assert_eq!(Some(45.5), opts.geo.latitude);
+    assert_eq!(Some(-100.0), opts.geo.longitude);
+    assert_eq!(
+        Some(Brightness::Percent(100)),
+        opts.brightness.day_brightness
+    );
+    assert_eq!(
+        Some(Brightness::Percent(30)),
+        opts.brightness.night_brightness
+    );
+    // lists are replaced rather than merged
+    assert_eq!(1, opts.devices.len());
+    assert_eq!(
+        Some(Brightness::Percent(70)),
+        opts.devices[0].day_brightness
+    );
+
+    // only the options asked for are returned
+    let env = env_overrides(
+        vars(&[("DLUX_GEO_LATITUDE", "45.5")]),
+        ENV_OPTIONS,
+        &mut warnings,
+    )
+    .unwrap();
+    assert!(env.is_empty());
+    assert!(warnings.is_empty());
+
+    // variables which aren't options, like those hooks set, are only warned about
+    let env = env_overrides(
+        vars(&[("DLUX_EVENT", "sunset"), ("DLUX_LATITUDE", "45.5")]),
+        &all,
+        &mut warnings,
+    )
+    .unwrap();
+    assert!(env.is_empty());
+    assert_eq!(2, warnings.len(), "{:?}", warnings);
+
+    let err = env_overrides(
+        vars(&[("DLUX_DEVICES", "[model: U2415"), ("DLUX_SCALE", "{")]),
+        &all,
+        &mut warnings,
+    )
+    .unwrap_err()
+    .to_string();
+    assert_eq!(2, err.lines().count(), "{}", err);
+    assert!(err.contains("$DLUX_DEVICES"), "{}", err);
+}
+
+/// A directory of files for a test, removed when it's dropped, even if the test panics.
+#[cfg(test)]
+struct TestDir(std::path::PathBuf);
+
+#[cfg(test)]
+impl TestDir {
+    fn new(name: &str) -> Self {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+        let dir = std::env::temp_dir().join(format!(
+            "dlux-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn test_env_precedence() {
+    let vars = vec![
+        ("DLUX_NIGHT_BRIGHTNESS".to_owned(), "30".to_owned()),
+        ("DLUX_PROFILE".to_owned(), "movie".to_owned()),
+        ("DLUX_EVENT".to_owned(), "sunset".to_owned()),
+    ];
+
+    // environment variables replace what's in the file
+    let dir = TestDir::new("env");
+    let path = dir.0.join("config.yaml");
+    std::fs::write(
+        &path,
+        "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\nprofile: default\n",
+    )
+    .unwrap();
+    let opts = Opts::from_file_with_vars(&path, None, vars.clone()).unwrap();
+    assert_eq!(
+        (
+            Some(Brightness::Percent(100)),
+            Some(Brightness::Percent(30))
+        ),
+        (
+            opts.brightness.day_brightness,
+            opts.brightness.night_brightness
+        )
+    );
+    assert_eq!(Some("movie"), opts.profile.as_deref());
+    assert_eq!(1, opts.warnings.len(), "{:?}", opts.warnings);
+
+    // and flags replace environment variables, which with_env leaves to them
+    let opts = Opts::from_iter(&["dlux", "--lat", "20", "--long=-100", "-n", "50"])
+        .with_vars(vars)
+        .unwrap();
+    assert_eq!(
+        Some(Brightness::Percent(50)),
+        opts.brightness.night_brightness
+    );
+    assert_eq!(Some("movie"), opts.profile.as_deref());
+}
+
+#[test]
+fn test_geo() {
+    let geo = |yaml: &str| serde_yaml::from_str::<GeoOpts>(yaml).unwrap().resolve();
+
+    let loc = geo("{latitude: -33.9, longitude: 151.2, altitude: 40}").unwrap();
+    assert_eq!(
+        (-33.9, 151.2, 40.0),
+        (loc.latitude, loc.longitude, loc.altitude)
+    );
+    assert!(geo("{latitude: -60, longitude: -180}").is_ok());
+
+    let err = |yaml| geo(yaml).unwrap_err().to_string();
+    assert!(err("{latitude: 100, longitude: 0}").contains("latitude 100 is out of range"));
+    assert!(err("{latitude: 0, longitude: 181}").contains("longitude 181 is out of range"));
+    assert!(err("{latitude: 20}").contains("must be given together"));
+    // including those derived from a timezone, like Arctic/Longyearbyen
+    assert!(err("{latitude: 90, longitude: 0}").contains("doesn't rise or set"));
+
+    let loc = geo("{latitude: \"40°42'46\\\"N\", longitude: 74.0W}").unwrap();
+    assert!((loc.latitude - 40.7128).abs() < 1e-3 && loc.longitude == -74.0);
+    let loc = geo("{location: '87G7PX7V+4H'}").unwrap();
+    assert!((loc.latitude - 40.7128).abs() < 1e-3, "{}", loc);
+    assert!(err("{location: '1,2', latitude: 1}").contains("only one of"));
+    let loc = geo("{city: 'Seattle, US'}").unwrap();
+    assert_eq!((47.6062, -122.3321), (loc.latitude, loc.longitude));
+    assert!(err("{city: Seattle, longitude: 1}").contains("only one of"));
+    assert!(serde_yaml::from_str::<GeoOpts>("{latitude: 74W}").is_err());
+
+    assert!(parse_latitude("-91").is_err());
+    assert_eq!(120.5, parse_longitude("120.5").unwrap());
+}
+
+#[test]
+fn test_formats() {
+    let yaml = "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\ndevices: [{model: U2415, night_brightness: 20}]\n";
+    let toml = r#"
+day_brightness = 100
+night_brightness = 40
+
+[geo]
+latitude = 20
+longitude = -100
+
+[[devices]]
+model = "U2415"
+night_brightness = 20
+"#;
+    let json = r#"{"geo": {"latitude": 20, "longitude": -100}, "day_brightness": 100,
+        "night_brightness": 40, "devices": [{"model": "U2415", "night_brightness": 20}]}"#;
+
+    for (raw, format) in &[
+        (yaml, Format::Yaml),
+        (toml, Format::Toml),
+        (json, Format::Json),
+    ] {
+        let cfg = Config::new(parse::<Opts>(raw, *format).unwrap()).unwrap();
+        assert_eq!(20.0, cfg.geo.latitude, "{:?}", format);
+        assert_eq!(2, cfg.devices.len(), "{:?}", format);
+        assert_eq!(
+            Brightness::Percent(20),
+            cfg.devices[0].night_brightness,
+            "{:?}",
+            format
+        );
+    }
+
+    let pos = |raw, format| parse::<Opts>(raw, format).unwrap_err().1;
+    assert_eq!(
+        Some((3, 14)),
+        pos("geo:\n  latitude: 20\n  longitude: [1]\n", Format::Yaml)
+    );
+    assert_eq!(
+        Some((3, 13)),
+        pos("[geo]\nlatitude = 20\nlongitude = [1]\n", Format::Toml)
+    );
+    assert_eq!(
+        Some((1, 29)),
+        pos(r#"{"geo": {"latitude": 20, "x"}}"#, Format::Json)
+    );
+
+    assert_eq!(
+        Format::Toml,
+        Format::from_path("/etc/dlux/config.TOML".as_ref())
+    );
+    assert_eq!(Format::Yaml, Format::from_path("/etc/dlux/config".as_ref()));
+}
+
+#[test]
+fn test_drop_ins() {
+    let dir = TestDir::new("config");
+    std::fs::create_dir_all(dir.0.join(DROP_IN_DIR)).unwrap();
+    std::fs::write(
+        dir.0.join("config.yaml"),
+        "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\ndevices: [{model: U2415}]\n",
+    )
+    .unwrap();
+    for (name, raw) in &[
+        (
+            "10-host.yaml",
+            "night_brightness: 30\ndevices: [{model: P2415, day_brightness: 50}]\n",
+        ),
+        ("20-geo.toml", "[geo]\nlatitude = 45\n"),
+        ("30-empty.yaml", ""),
+        ("README", "not a config"),
+    ] {
+        std::fs::write(dir.0.join(DROP_IN_DIR).join(name), raw).unwrap();
     }
+
+    // without the process's environment, which may set options too
+    let opts = Opts::from_file_with_vars(&dir.0.join("config.yaml"), None, vec![]).unwrap();
+    let cfg = Config::new(opts).unwrap();
+
+    assert_eq!(45.0, cfg.geo.latitude);
+    assert_eq!(-100.0, cfg.geo.longitude);
+    let models = cfg
+        .devices
+        .iter()
+        .map(|d| d.matcher.model.as_ref().map(|m| m.as_str()))
+        .collect::<Vec<_>>();
+    assert_eq!(vec![Some("P2415"), Some("U2415"), None], models);
+    assert_eq!(Brightness::Percent(50), cfg.devices[0].day_brightness);
+    assert_eq!(Brightness::Percent(30), cfg.devices[1].night_brightness);
+}
+
+#[test]
+fn test_merged_positions() {
+    let dir = TestDir::new("merged");
+    std::fs::create_dir_all(dir.0.join(DROP_IN_DIR)).unwrap();
+    let path = dir.0.join("config.yaml");
+    std::fs::write(
+        &path,
+        "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\ndevices:\n  - model: U2415\n    calibration: {}\n",
+    )
+    .unwrap();
+    let drop_in = dir.0.join(DROP_IN_DIR).join("10-host.toml");
+    std::fs::write(
+        &drop_in,
+        "[[devices]]\nmodel = \"P2415Q\"\n\n[[devices]]\nmodel = \"P2715Q\"\ncalibration = {}\n",
+    )
+    .unwrap();
+    let vars = vec![(
+        "DLUX_HOOKS".to_owned(),
+        "[{command: 'true', events: [nope]}]".to_owned(),
+    )];
+
+    let opts = Opts::from_file_with_vars(&path, None, vars).unwrap();
+    let err = Config::new(opts).unwrap_err().to_string();
+    assert_eq!(
+        vec![
+            format!(
+                "{}:4:1: rule 2: calibration needs a gamma or points",
+                drop_in.display()
+            ),
+            format!(
+                "{}:5:5: rule 3: calibration needs a gamma or points",
+                path.display()
+            ),
+            format!(
+                "$DLUX_HOOKS:1:2: hook 1: unknown event \"nope\", expected one of {}",
+                crate::hooks::EVENTS.join(", ")
+            ),
+        ],
+        err.lines().collect::<Vec<_>>()
+    );
+
+    // type errors are found in the file they're in, rather than once it's merged
+    std::fs::write(&drop_in, "[[devices]]\nmodel = 3\n").unwrap();
+    let err = Opts::from_file_with_vars(&path, None, vec![])
+        .unwrap_err()
+        .to_string();
+    assert!(
+        err.starts_with(&format!("{}:1:1: ", drop_in.display())),
+        "{}",
+        err
+    );
+    std::fs::remove_file(&drop_in).unwrap();
+    let vars = vec![("DLUX_MAX_CONCURRENT_HOOKS".to_owned(), "many".to_owned())];
+    let err = Opts::from_file_with_vars(&path, None, vars)
+        .unwrap_err()
+        .to_string();
+    assert!(err.starts_with("$DLUX_MAX_CONCURRENT_HOOKS: "), "{}", err);
+}
+
+#[test]
+fn test_problem_positions() {
+    let yaml = "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\nnight_brightness: 40\ndevices:\n  - model: U2415\n  - model: P2415Q\n    calibration: {}\nmax_concurrent_hooks: 0\n";
+    let toml = r#"day_brightness = 100
+night_brightness = 40
+max_concurrent_hooks = 0
+
+[geo]
+latitude = 20
+longitude = -100
+
+[[devices]]
+model = "U2415"
+
+[[devices]]
+model = "P2415Q"
+calibration = {}
+"#;
+    let json = r#"{"geo": {"latitude": 20, "longitude": -100}, "day_brightness": 100,
+  "night_brightness": 40, "max_concurrent_hooks": 0,
+  "devices": [{"model": "U2415"}, {"model": "P2415Q", "calibration": {}}]}"#;
+
+    for (raw, format, rule, hooks) in &[
+        (yaml, Format::Yaml, "6:5", "8:23"),
+        (toml, Format::Toml, "12:1", "3:24"),
+        (json, Format::Json, "3:35", "2:51"),
+    ] {
+        let source = Source {
+            name: "config".to_owned(),
+            raw: raw.to_string(),
+            format: *format,
+        };
+        let opts: Opts = source.parse().unwrap();
+        let err = Config::new(Opts {
+            sources: Sources(vec![Fragment::file(source).unwrap()]),
+            ..opts
+        })
+        .unwrap_err()
+        .to_string();
+        assert_eq!(
+            vec![
+                format!(
+                    "config:{}: rule 2: calibration needs a gamma or points",
+                    rule
+                ),
+                format!("config:{}: max_concurrent_hooks must be at least 1", hooks),
+            ],
+            err.lines().collect::<Vec<_>>(),
+            "{:?}",
+            format
+        );
+    }
+
+    // options which aren't set are pointed to by the file alone
+    let source = Source {
+        name: "config".to_owned(),
+        raw: "geo: {latitude: 20, longitude: -100}\nday_brightness: 100\n".to_owned(),
+        format: Format::Yaml,
+    };
+    let sources = Sources(vec![Fragment::file(source).unwrap()]);
+    assert_eq!(
+        Some("config:2:17".to_owned()),
+        sources.locate(&keys(&["day_brightness"]))
+    );
+    assert_eq!(
+        Some("config".to_owned()),
+        sources.locate(&keys(&["night_brightness"]))
+    );
+    assert_eq!(
+        Some("config".to_owned()),
+        sources.locate(&[Key::Name("devices".to_owned()), Key::Index(0)])
+    );
 }
@@ -0,0 +1,198 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{logging::*, types::*};
+
+/// A command sent to the daemon over its control socket. Requests are a single line,
+/// and replies are a line of "ok" or "error" followed by a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    /// Reports the active profile and those available.
+    Profiles,
+    /// Switches to the named profile.
+    SetProfile(String),
+}
+
+impl FromStr for Request {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut words = s.split_whitespace();
+        match (words.next(), words.next(), words.next()) {
+            (Some("profile"), None, None) => Ok(Self::Profiles),
+            (Some("profile"), Some(name), None) => Ok(Self::SetProfile(name.to_owned())),
+            _ => Err(format_err!("unknown request {:?}", s.trim())),
+        }
+    }
+}
+
+impl std::fmt::Display for Request {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Profiles => write!(f, "profile"),
+            Self::SetProfile(name) => write!(f, "profile {}", name),
+        }
+    }
+}
+
+/// The control socket used unless another is configured: control.sock in
+/// $RUNTIME_DIRECTORY when run by systemd, otherwise in $XDG_RUNTIME_DIR/dlux or
+/// /run/dlux.
+pub fn default_socket_path() -> PathBuf {
+    let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+
+    let dir = match var("RUNTIME_DIRECTORY") {
+        // systemd separates multiple directories with colons.
+        Some(dirs) => PathBuf::from(dirs.to_string_lossy().split(':').next().unwrap_or("")),
+        None => var("XDG_RUNTIME_DIR")
+            .map(|d| PathBuf::from(d).join("dlux"))
+            .unwrap_or_else(|| PathBuf::from("/run/dlux")),
+    };
+    dir.join("control.sock")
+}
+
+/// Server accepts requests on the control socket. The socket is removed when it's
+/// dropped.
+pub struct Server {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl Server {
+    /// Listens at path. A socket left behind by a daemon which didn't exit cleanly is
+    /// replaced, but one which is still in use is an error.
+    pub async fn bind(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(format_err!(
+                    "control socket {:?} is in use by another daemon",
+                    path
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("failed to listen on control socket {:?}", path))?;
+        Ok(Self {
+            listener,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Waits for the next request. Connections closed without one, like those of
+    /// another daemon checking whether the socket is in use, are ignored.
+    pub async fn accept(&self) -> Result<(Request, Connection)> {
+        let (stream, line) = loop {
+            let (stream, _) = self.listener.accept().await?;
+            let mut stream = BufReader::new(stream);
+
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? > 0 {
+                break (stream, line);
+            }
+        };
+        let conn = Connection { stream };
+        match line.parse() {
+            Ok(req) => Ok((req, conn)),
+            Err(e) => {
+                let msg = e.to_string();
+                conn.reply(Err(e)).await?;
+                Err(format_err!("bad control request: {}", msg))
+            }
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            debug!("failed to remove control socket {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Connection is a client awaiting the reply to its request.
+pub struct Connection {
+    stream: BufReader<UnixStream>,
+}
+
+impl Connection {
+    pub async fn reply(mut self, res: Result<String>) -> Result<()> {
+        let reply = match res {
+            Ok(msg) => format!("ok\n{}", msg),
+            Err(e) => format!("error\n{}", e),
+        };
+        self.stream.get_mut().write_all(reply.as_bytes()).await?;
+        self.stream.get_mut().shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Sends the request to the daemon listening at path and returns its reply.
+pub async fn send(path: &Path, req: &Request) -> Result<String> {
+    let mut stream = UnixStream::connect(path).await.with_context(|| {
+        format!(
+            "failed to connect to control socket {:?}; is the daemon running?",
+            path
+        )
+    })?;
+    stream.write_all(format!("{}\n", req).as_bytes()).await?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).await?;
+    match reply.split_once('\n') {
+        Some(("ok", msg)) => Ok(msg.to_owned()),
+        Some(("error", msg)) => Err(format_err!("{}", msg)),
+        _ => Err(format_err!("unexpected reply from daemon: {:?}", reply)),
+    }
+}
+
+#[tokio::test]
+async fn test_control_socket() {
+    let path = std::env::temp_dir()
+        .join(format!("dlux-control-{}", std::process::id()))
+        .join("control.sock");
+    let server = Server::bind(&path).await.unwrap();
+    assert!(Server::bind(&path).await.is_err());
+
+    let client = tokio::spawn({
+        let path = path.clone();
+        async move {
+            (
+                send(&path, &Request::SetProfile("presentation".to_owned())).await,
+                send(&path, &Request::Profiles).await,
+            )
+        }
+    });
+
+    let (req, conn) = server.accept().await.unwrap();
+    assert_eq!(Request::SetProfile("presentation".to_owned()), req);
+    conn.reply(Err(format_err!("no profile named presentation")))
+        .await
+        .unwrap();
+    let (req, conn) = server.accept().await.unwrap();
+    assert_eq!(Request::Profiles, req);
+    conn.reply(Ok("default\n".to_owned())).await.unwrap();
+
+    let (set, list) = client.await.unwrap();
+    assert_eq!(
+        "no profile named presentation",
+        set.unwrap_err().to_string()
+    );
+    assert_eq!("default\n", list.unwrap());
+
+    drop(server);
+    assert!(!path.exists());
+    std::fs::remove_dir(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,132 @@
+use std::fs::{read_dir, read_to_string};
+use std::path::Path;
+
+use super::I2CDevice;
+use crate::{config::BusOpts, logging::*, types::*};
+
+const SYSFS_I2C: &str = "/sys/bus/i2c/devices";
+
+/// Prefixes of i2c adapter names which never carry DDC, like SMBus and GPU power
+/// management. Starts with ddcutil's list of ignorable adapters.
+pub(crate) const NON_DDC_ADAPTERS: &[&str] = &[
+    "SMBus",
+    "soc:i2cdsi",
+    "smu",
+    "mac-io",
+    "u4",
+    "AMDGPU SMU",
+    "Synopsys DesignWare",
+];
+
+/// Bus is an i2c adapter which may have a display on it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bus {
+    /// Device node path, e.g. /dev/i2c-3.
+    pub path: String,
+    /// Adapter name reported by the kernel driver, e.g. "AMDGPU DM i2c hw bus 1".
+    /// Empty if it couldn't be read.
+    pub adapter: String,
+}
+
+/// Lists i2c buses which pass the bus filters, without opening them. Adapters are read
+/// from sysfs, falling back to /dev if it's unavailable.
+pub fn buses(opts: &BusOpts) -> Result<Vec<Bus>> {
+    let all = match list_sysfs(Path::new(SYSFS_I2C)) {
+        Ok(buses) => buses,
+        Err(e) => {
+            debug!(
+                "failed to list i2c adapters in {}, falling back to /dev: {}",
+                SYSFS_I2C, e
+            );
+            list_dev()?
+        }
+    };
+
+    Ok(all
+        .into_iter()
+        .filter(|bus| match opts.allows(&bus.path, &bus.adapter) {
+            Ok(()) => true,
+            Err(why) => {
+                debug!("skipping {} ({:?}): {}", bus.path, bus.adapter, why);
+                false
+            }
+        })
+        .collect())
+}
+
+impl Bus {
+    /// Opens the bus. Whether there's a display on it is only known once it's queried.
+    pub fn open(&self) -> Result<I2CDevice> {
+        Ok(ddc_i2c::from_i2c_device(&self.path)?)
+    }
+}
+
+fn list_sysfs(root: &Path) -> Result<Vec<Bus>> {
+    let mut buses = vec![];
+
+    for entry in read_dir(root)? {
+        let entry = entry?;
+        // Clients of adapters, like 0-0050, are listed alongside them.
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with("i2c-") {
+            continue;
+        }
+
+        let adapter = read_to_string(entry.path().join("name"))
+            .map(|n| n.trim().to_owned())
+            .unwrap_or_default();
+        buses.push(Bus {
+            path: format!("/dev/{}", name),
+            adapter,
+        });
+    }
+
+    buses.sort_by_key(|b| bus_number(&b.path));
+    Ok(buses)
+}
+
+fn list_dev() -> Result<Vec<Bus>> {
+    let mut buses = read_dir("/dev")?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().into_owned())
+        .filter(|n| n.starts_with("i2c-"))
+        .map(|n| Bus {
+            path: format!("/dev/{}", n),
+            adapter: String::new(),
+        })
+        .collect::<Vec<_>>();
+
+    buses.sort_by_key(|b| bus_number(&b.path));
+    Ok(buses)
+}
+
+fn bus_number(path: &str) -> Option<u32> {
+    path.rsplit('-').next()?.parse().ok()
+}
+
+#[test]
+fn test_list_sysfs() {
+    let root = std::env::temp_dir().join(format!("dlux-i2c-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    for (dir, name) in &[
+        ("i2c-10", "AMDGPU DM i2c hw bus 1\n"),
+        ("i2c-2", "SMBus PIIX4 adapter port 0 at 0b00\n"),
+        ("0-0050", "eeprom\n"),
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+        std::fs::write(root.join(dir).join("name"), name).unwrap();
+    }
+
+    let buses = list_sysfs(&root).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    assert_eq!(
+        vec!["/dev/i2c-2", "/dev/i2c-10"],
+        buses.iter().map(|b| b.path.as_str()).collect::<Vec<_>>()
+    );
+    assert_eq!("AMDGPU DM i2c hw bus 1", buses[1].adapter);
+
+    let opts = BusOpts::default();
+    assert!(opts.allows(&buses[0].path, &buses[0].adapter).is_err());
+    assert!(opts.allows(&buses[1].path, &buses[1].adapter).is_ok());
+}
@@ -0,0 +1,190 @@
+use std::collections::BTreeMap;
+
+use crate::types::*;
+
+/// Capabilities is a parsed MCCS capabilities string, which a display returns over
+/// DDC/CI to describe which commands and VCP features it supports.
+///
+/// For example: `(prot(monitor)type(lcd)model(U2415)cmds(01 02 03 07 0C E3 F3)vcp(02 04 10
+/// 12 14(05 08 0B) 60(01 0F 11))mccs_ver(2.1))`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Protocol class, usually "monitor".
+    pub protocol: Option<String>,
+    /// Display technology, e.g. "lcd" or "crt".
+    pub display_type: Option<String>,
+    pub model: Option<String>,
+    pub mccs_version: Option<MccsVersion>,
+    /// Supported DDC/CI command opcodes.
+    pub commands: Vec<u8>,
+    /// Supported VCP feature codes. Non-continuous features list their allowed values.
+    pub vcp: BTreeMap<u8, Vec<u8>>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MccsVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl Capabilities {
+    /// Parses a capabilities string. Unknown entries are ignored, while malformed
+    /// known entries are errors.
+    pub fn parse(caps: &str) -> Result<Self> {
+        let caps = caps.trim().trim_end_matches('\0');
+        // Some displays omit the enclosing parentheses.
+        let inner = match caps.strip_prefix('(') {
+            Some(c) => c.strip_suffix(')').unwrap_or(c),
+            None => caps,
+        };
+
+        let mut res = Capabilities::default();
+        for (key, value) in entries(inner)? {
+            match key.as_str() {
+                "prot" => res.protocol = Some(value.trim().to_owned()),
+                "type" => res.display_type = Some(value.trim().to_owned()),
+                "model" => res.model = Some(value.trim().to_owned()),
+                "mccs_ver" => res.mccs_version = Some(parse_version(&value)?),
+                "cmds" => {
+                    res.commands = parse_codes(&value)?
+                        .into_iter()
+                        .map(|(code, _)| code)
+                        .collect()
+                }
+                "vcp" => res.vcp = parse_codes(&value)?.into_iter().collect(),
+                _ => (),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Whether the display claims to support the VCP feature code.
+    pub fn supports(&self, code: u8) -> bool {
+        self.vcp.contains_key(&code)
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "type {}, MCCS {}, VCP features {}",
+            self.display_type.as_deref().unwrap_or("unknown"),
+            self.mccs_version
+                .map(|v| format!("{}.{}", v.major, v.minor))
+                .unwrap_or_else(|| "unknown".to_owned()),
+            self.vcp
+                .keys()
+                .map(|c| format!("{:02X}", c))
+                .collect::<Vec<_>>()
+                .join(" ")
+        )
+    }
+}
+
+/// entries splits `key(value)key2(value2)` into its keys and values. Values may
+/// contain nested parentheses.
+fn entries(caps: &str) -> Result<Vec<(String, String)>> {
+    let mut res = vec![];
+    let mut rest = caps;
+
+    while !rest.trim().is_empty() {
+        let open = rest
+            .find('(')
+            .ok_or_else(|| format_err!("expected '(' after {:?}", rest.trim()))?;
+        let key = rest[..open].trim().to_lowercase();
+
+        let mut depth = 0;
+        let mut close = None;
+        for (i, c) in rest[open..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        close = Some(open + i);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+        let close = close.ok_or_else(|| format_err!("unterminated capability {:?}", key))?;
+
+        res.push((key, rest[open + 1..close].to_owned()));
+        rest = &rest[close + 1..];
+    }
+
+    Ok(res)
+}
+
+/// parse_codes parses a list of hex codes, each optionally followed by a parenthesized
+/// list of hex values. Whitespace between codes is optional.
+fn parse_codes(list: &str) -> Result<Vec<(u8, Vec<u8>)>> {
+    let mut res: Vec<(u8, Vec<u8>)> = vec![];
+    let mut chars = list.chars().filter(|c| !c.is_whitespace());
+
+    while let Some(c) = chars.next() {
+        if c == '(' {
+            let values = chars.by_ref().take_while(|c| *c != ')').collect::<String>();
+            let last = res
+                .last_mut()
+                .ok_or_else(|| format_err!("values without a code in {:?}", list))?;
+            last.1 = parse_codes(&values)?
+                .into_iter()
+                .map(|(code, _)| code)
+                .collect();
+            continue;
+        }
+
+        let hex = [Some(c), chars.next()].iter().flatten().collect::<String>();
+        let code = u8::from_str_radix(&hex, 16)
+            .map_err(|e| format_err!("invalid code {:?} in {:?}: {}", hex, list, e))?;
+        res.push((code, vec![]));
+    }
+
+    Ok(res)
+}
+
+fn parse_version(ver: &str) -> Result<MccsVersion> {
+    let (major, minor) = ver
+        .trim()
+        .split_once('.')
+        .ok_or_else(|| format_err!("invalid MCCS version {:?}", ver))?;
+
+    Ok(MccsVersion {
+        major: major.parse()?,
+        minor: minor.parse()?,
+    })
+}
+
+#[test]
+fn test_parse_capabilities() {
+    let caps = Capabilities::parse(
+        "(prot(monitor)type(LCD)model(U2415)cmds(01 02 03 07 0C E3 F3)vcp(02 04 05 08 10 12 14(05 08 0B 0C) 60( 01 0F 11) DF)mccs_ver(2.1)mswhql(1))",
+    )
+    .unwrap();
+
+    assert_eq!(Some("monitor"), caps.protocol.as_deref());
+    assert_eq!(Some("LCD"), caps.display_type.as_deref());
+    assert_eq!(Some("U2415"), caps.model.as_deref());
+    assert_eq!(Some(MccsVersion { major: 2, minor: 1 }), caps.mccs_version);
+    assert_eq!(
+        vec![0x01, 0x02, 0x03, 0x07, 0x0c, 0xe3, 0xf3],
+        caps.commands
+    );
+    assert!(caps.supports(0x10));
+    assert!(!caps.supports(0x11));
+    assert_eq!(vec![0x05, 0x08, 0x0b, 0x0c], caps.vcp[&0x14]);
+    assert_eq!(vec![0x01, 0x0f, 0x11], caps.vcp[&0x60]);
+
+    // no enclosing parentheses or spaces between codes
+    let caps = Capabilities::parse("vcp(0210AC)type(lcd)").unwrap();
+    assert_eq!(
+        vec![0x02, 0x10, 0xac],
+        caps.vcp.keys().copied().collect::<Vec<_>>()
+    );
+
+    assert!(Capabilities::parse("(vcp(02 10)").is_err());
+}
@@ -0,0 +1,271 @@
+use serde::{Deserialize, Serialize};
+
+use crate::types::*;
+
+/// CIE constants for converting between lightness and relative luminance.
+const CIE_EPSILON: f64 = 216.0 / 24389.0;
+const CIE_KAPPA: f64 = 24389.0 / 27.0;
+
+/// How configured brightness percentages are interpreted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    /// a percentage of the display's maximum luminance.
+    #[default]
+    Linear,
+    /// a percentage of perceived lightness (CIE L*), so each step looks as large as the
+    /// last and calibrated displays at the same percentage look alike.
+    Perceptual,
+}
+
+impl Scale {
+    /// The luminance relative to the maximum for a brightness between 0 and 1.
+    pub fn luminance(self, brightness: f64) -> f64 {
+        match self {
+            Self::Linear => brightness,
+            Self::Perceptual => {
+                let lightness = brightness * 100.0;
+                if lightness > CIE_KAPPA * CIE_EPSILON {
+                    ((lightness + 16.0) / 116.0).powi(3)
+                } else {
+                    lightness / CIE_KAPPA
+                }
+            }
+        }
+    }
+
+    /// The brightness between 0 and 1 of a relative luminance, the inverse of luminance.
+    pub fn brightness(self, luminance: f64) -> f64 {
+        match self {
+            Self::Linear => luminance,
+            Self::Perceptual if luminance > CIE_EPSILON => {
+                (116.0 * luminance.cbrt() - 16.0) / 100.0
+            }
+            Self::Perceptual => luminance * CIE_KAPPA / 100.0,
+        }
+    }
+}
+
+impl std::str::FromStr for Scale {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "linear" => Ok(Self::Linear),
+            "perceptual" => Ok(Self::Perceptual),
+            _ => Err(format_err!(
+                "unknown brightness scale {:?}, expected linear or perceptual",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Scale {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Linear => write!(f, "linear"),
+            Self::Perceptual => write!(f, "perceptual"),
+        }
+    }
+}
+
+/// How a display's luminance responds to its raw brightness value.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Response {
+    /// luminance is the raw value's fraction of the maximum raised to the power.
+    Gamma { gamma: f64 },
+    /// raw values measured for brightness percentages, sorted and interpolated
+    /// between. They map percentages straight to raw values, whatever the scale.
+    Points { points: Vec<(f64, u16)> },
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self::Gamma { gamma: 1.0 }
+    }
+}
+
+/// BrightnessCurve converts between configured brightness and a display's raw values.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct BrightnessCurve {
+    pub scale: Scale,
+    #[serde(flatten)]
+    pub response: Response,
+    /// raw values are kept at or above this.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_raw: Option<u16>,
+    /// raw values are kept at or below this, if it's under the display's maximum.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_raw: Option<u16>,
+}
+
+impl BrightnessCurve {
+    /// The raw value for a brightness between 0 and 1, given the display's maximum.
+    pub fn raw(&self, brightness: f64, max: u16) -> u16 {
+        let brightness = brightness.clamp(0.0, 1.0);
+        let raw = match &self.response {
+            Response::Gamma { gamma } => {
+                self.scale.luminance(brightness).powf(1.0 / gamma) * max as f64
+            }
+            Response::Points { points } => interpolate(
+                points.iter().map(|&(p, raw)| (p / 100.0, raw as f64)),
+                brightness,
+            ),
+        };
+        let highest = self.max_raw.unwrap_or(max).min(max);
+        let lowest = self.min_raw.unwrap_or(0).min(highest);
+        raw.round().clamp(lowest as f64, highest as f64) as u16
+    }
+
+    /// The brightness between 0 and 1 of a raw value, the inverse of raw.
+    pub fn brightness(&self, raw: u16, max: u16) -> f64 {
+        if max == 0 {
+            return 0.0;
+        }
+        match &self.response {
+            Response::Gamma { gamma } => self
+                .scale
+                .brightness((raw as f64 / max as f64).min(1.0).powf(*gamma)),
+            Response::Points { points } => interpolate(
+                points.iter().map(|&(p, raw)| (raw as f64, p / 100.0)),
+                raw as f64,
+            ),
+        }
+    }
+}
+
+/// How bright a display is along its raw brightness range, in cd/m² (nits).
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(untagged)]
+pub enum Luminance {
+    /// luminance at the lowest and highest raw values, following the display's
+    /// brightness curve between them.
+    Range { min_nits: f64, max_nits: f64 },
+    /// luminance measured at raw values, interpolated between.
+    Points { points: Vec<(u16, f64)> },
+}
+
+impl Luminance {
+    /// The brightness between 0 and 1 along the curve at which the display is as
+    /// bright as the luminance, or as close as it gets.
+    pub fn brightness(&self, nits: f64, curve: &BrightnessCurve, max: u16) -> f64 {
+        match self {
+            Self::Range { min_nits, max_nits } => {
+                let relative = (nits - min_nits) / (max_nits - min_nits);
+                curve.scale.brightness(relative.clamp(0.0, 1.0))
+            }
+            Self::Points { points } => {
+                let raw = interpolate(points.iter().map(|&(raw, n)| (n, raw as f64)), nits);
+                curve.brightness(raw.round() as u16, max)
+            }
+        }
+    }
+
+    /// The dimmest and brightest the display gets, in nits.
+    pub fn range(&self) -> (f64, f64) {
+        match self {
+            Self::Range { min_nits, max_nits } => (*min_nits, *max_nits),
+            Self::Points { points } => (
+                points.first().map(|p| p.1).unwrap_or_default(),
+                points.last().map(|p| p.1).unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+/// Linearly interpolates y at x between points sorted by x. Outside of them, the
+/// nearest point's y is used.
+fn interpolate<I: Iterator<Item = (f64, f64)>>(points: I, x: f64) -> f64 {
+    let mut prev: Option<(f64, f64)> = None;
+    for (px, py) in points {
+        if x <= px {
+            return match prev {
+                Some((qx, qy)) => qy + (py - qy) * (x - qx) / (px - qx),
+                None => py,
+            };
+        }
+        prev = Some((px, py));
+    }
+    prev.map(|(_, y)| y).unwrap_or_default()
+}
+
+#[test]
+fn test_curve() {
+    let close = |expected: f64, actual: f64| (expected - actual).abs() < 1e-3;
+
+    let linear = BrightnessCurve::default();
+    assert_eq!(40, linear.raw(0.4, 100));
+    assert_eq!(0.4, linear.brightness(40, 100));
+    assert_eq!(100, linear.raw(1.5, 100));
+
+    // half of perceived lightness is under a fifth of the luminance
+    let perceptual = BrightnessCurve {
+        scale: Scale::Perceptual,
+        ..BrightnessCurve::default()
+    };
+    assert_eq!(184, perceptual.raw(0.5, 1000));
+    assert!(close(0.5, perceptual.brightness(184, 1000)));
+    assert_eq!(1, perceptual.raw(0.05, 100));
+    assert_eq!(1000, perceptual.raw(1.0, 1000));
+
+    let gamma = BrightnessCurve {
+        scale: Scale::Perceptual,
+        response: Response::Gamma { gamma: 2.0 },
+        ..BrightnessCurve::default()
+    };
+    assert_eq!(429, gamma.raw(0.5, 1000));
+    assert!(close(0.5, gamma.brightness(429, 1000)));
+
+    let points = BrightnessCurve {
+        scale: Scale::Perceptual,
+        response: Response::Points {
+            points: vec![(10.0, 5), (50.0, 25), (100.0, 80)],
+        },
+        ..BrightnessCurve::default()
+    };
+    assert_eq!(5, points.raw(0.0, 100));
+    assert_eq!(15, points.raw(0.3, 100));
+    assert_eq!(80, points.raw(1.0, 100));
+    assert!(close(0.3, points.brightness(15, 100)));
+    assert!(close(1.0, points.brightness(100, 100)));
+
+    let clamped = BrightnessCurve {
+        min_raw: Some(3),
+        max_raw: Some(90),
+        ..BrightnessCurve::default()
+    };
+    assert_eq!(3, clamped.raw(0.01, 100));
+    assert_eq!(50, clamped.raw(0.5, 100));
+    assert_eq!(90, clamped.raw(1.0, 100));
+    assert_eq!(50, clamped.raw(1.0, 50));
+}
+
+#[test]
+fn test_luminance() {
+    let close = |expected: f64, actual: f64| (expected - actual).abs() < 1e-3;
+    let curve = BrightnessCurve::default();
+
+    let range = Luminance::Range {
+        min_nits: 50.0,
+        max_nits: 350.0,
+    };
+    assert!(close(0.5, range.brightness(200.0, &curve, 100)));
+    assert_eq!(0.0, range.brightness(10.0, &curve, 100));
+    assert_eq!(1.0, range.brightness(1000.0, &curve, 100));
+    // the same luminance is further along a perceptual scale
+    let perceptual = BrightnessCurve {
+        scale: Scale::Perceptual,
+        ..BrightnessCurve::default()
+    };
+    let b = range.brightness(200.0, &perceptual, 100);
+    assert!(close(0.5, perceptual.scale.luminance(b)), "{}", b);
+
+    let points = Luminance::Points {
+        points: vec![(0, 40.0), (50, 120.0), (100, 300.0)],
+    };
+    assert!(close(0.3, points.brightness(88.0, &curve, 100)));
+    assert!(close(0.75, points.brightness(210.0, &curve, 100)));
+    assert_eq!((40.0, 300.0), points.range());
+}
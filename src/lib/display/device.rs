@@ -1,17 +1,30 @@
 use std::convert::TryFrom;
 use std::fs::{read_link, File};
 
-use ddc::Ddc;
 use ddc_i2c::I2cDdc;
 use i2c_linux::I2c;
 
-use crate::{logging::*, prelude::*, types::*};
+use super::{curve::BrightnessCurve, scheduler::Scheduler};
+use crate::{
+    config::{DdcTiming, EdidOverride},
+    logging::*,
+    prelude::*,
+    types::*,
+};
+
+/// VCP feature code of the display's luminance.
+pub const BRIGHTNESS: u8 = 0x10;
 
 pub type I2CDevice = I2cDdc<I2c<File>>;
 pub struct Device {
     name: String,
-    inner: I2CDevice,
+    inner: Scheduler,
     max: Option<u16>,
+    curve: BrightnessCurve,
+    info: Option<DeviceInfo>,
+    edid_override: Option<EdidOverride>,
+    connector: Option<Connector>,
+    capabilities: Option<Capabilities>,
 }
 
 impl TryFrom<I2CDevice> for Device {
@@ -30,53 +43,85 @@ impl TryFrom<I2CDevice> for Device {
 
         Ok(Self {
             name,
-            inner: dev,
+            inner: Scheduler::new(dev),
             max: None,
+            curve: BrightnessCurve::default(),
+            info: None,
+            edid_override: None,
+            connector: None,
+            capabilities: None,
         })
     }
 }
 
+/// How close a read back brightness must be to what was written. Some displays round
+/// to the nearest step they support.
+pub const BRIGHTNESS_TOLERANCE: u16 = 1;
+
+/// The result of setting a device's brightness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// The device was already at the target, so nothing was written.
+    Skipped,
+    /// The target was written but couldn't be read back.
+    Written,
+    /// The target was written and read back within tolerance.
+    Verified,
+    /// The target was written but the device reports a different value.
+    Mismatched { expected: u16, actual: u16 },
+}
+
+impl std::fmt::Display for WriteOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Skipped => write!(f, "already at target"),
+            Self::Written => write!(f, "written, unverified"),
+            Self::Verified => write!(f, "written and verified"),
+            Self::Mismatched { expected, actual } => write!(
+                f,
+                "written but reads back {} instead of {}",
+                actual, expected
+            ),
+        }
+    }
+}
+
 pub trait BrightnessHardware {
     /// Get the device's current relative brightness pecentage or return an error.
+    /// It's relative to the device's max along its brightness curve.
     fn brightness(&mut self) -> Result<f64>;
-    /// Idempotently set brightness of display to the passed relative percentage of devices' max.
-    fn set_brightness(&mut self, b: f64) -> Result<()>;
+    /// Idempotently set brightness of display to the passed relative percentage of devices' max,
+    /// converted to a raw value along its brightness curve.
+    /// The current brightness is read first so that displays already at the target aren't
+    /// written to, then read back after writing.
+    fn set_brightness(&mut self, b: f64) -> Result<WriteOutcome>;
     /// Returns the raw, whole number maximum brightness value for the device.
     fn max_brightness(&mut self) -> Result<u16>;
 }
 
 impl BrightnessHardware for Device {
     fn brightness(&mut self) -> Result<f64> {
-        let cap = self.inner.get_vcp_feature(0x10)?;
+        let cap = self.inner.get_vcp_feature(BRIGHTNESS)?;
+        let max = self
+            .max_brightness()
+            .context("couldn't calculate relative percentage")?;
 
-        Ok((cap.value() as f64)
-            / (self
-                .max_brightness()
-                .context("couldn't calculate relative percentage")? as f64))
+        Ok(self.curve.brightness(cap.value(), max))
     }
 
-    fn set_brightness(&mut self, b: f64) -> Result<()> {
-        let rel_b = (b * self.max_brightness()? as f64) as u16;
+    fn set_brightness(&mut self, b: f64) -> Result<WriteOutcome> {
+        let max = self.max_brightness()?;
+        let rel_b = self.curve.raw(b, max);
+        let outcome = write_brightness(&mut self.inner, &self.name, rel_b)?;
 
-        match self.inner.set_vcp_feature(0x10, rel_b) {
-            Ok(_) => {
-                debug!(
-                    "set brightness for {} to {}% (absolute {})",
-                    self,
-                    b * 100.0,
-                    rel_b,
-                );
-                Ok(())
-            }
-            Err(e) => {
-                error!("failed to set monitor {} to {}%: {}", self, rel_b, e);
-                Err(format_err!(
-                    "failed to apply maximum brightness for {}: {}",
-                    self,
-                    e
-                ))
-            }
-        }
+        debug!(
+            "set brightness for {} to {}% (absolute {}): {}",
+            self,
+            b * 100.0,
+            rel_b,
+            outcome,
+        );
+        Ok(outcome)
     }
 
     fn max_brightness(&mut self) -> Result<u16> {
@@ -84,7 +129,7 @@ impl BrightnessHardware for Device {
             return Ok(max);
         }
 
-        match self.inner.get_vcp_feature(0x10) {
+        match self.inner.get_vcp_feature(BRIGHTNESS) {
             Ok(cap) => {
                 let max = cap.maximum();
                 self.max = Some(max);
@@ -99,21 +144,251 @@ impl BrightnessHardware for Device {
     }
 }
 
+/// The raw brightness of a device, as read and written over DDC/CI.
+trait RawBrightness {
+    fn read(&mut self) -> Result<u16>;
+    fn write(&mut self, value: u16) -> Result<()>;
+}
+
+impl RawBrightness for Scheduler {
+    fn read(&mut self) -> Result<u16> {
+        Ok(self.get_vcp_feature(BRIGHTNESS)?.value())
+    }
+
+    fn write(&mut self, value: u16) -> Result<()> {
+        self.set_vcp_feature(BRIGHTNESS, value)
+    }
+}
+
+/// Writes the raw brightness unless the device already reads within tolerance of it,
+/// then reads it back.
+fn write_brightness<D: RawBrightness>(
+    dev: &mut D,
+    name: &str,
+    target: u16,
+) -> Result<WriteOutcome> {
+    let close = |v: u16| (v as i32 - target as i32).abs() <= BRIGHTNESS_TOLERANCE as i32;
+
+    // Many displays persist brightness to EEPROM on every write.
+    match dev.read() {
+        Ok(cur) if close(cur) => {
+            debug!(
+                "brightness for {} is already {} (target {}), skipping write",
+                name, cur, target
+            );
+            return Ok(WriteOutcome::Skipped);
+        }
+        Ok(cur) => trace!("brightness for {} is {}", name, cur),
+        Err(e) => debug!(
+            "failed to read brightness for {} before writing: {}",
+            name, e
+        ),
+    }
+
+    if let Err(e) = dev.write(target) {
+        error!("failed to set monitor {} to {}: {}", name, target, e);
+        return Err(format_err!(
+            "failed to apply maximum brightness for {}: {}",
+            name,
+            e
+        ));
+    }
+
+    Ok(match dev.read() {
+        Ok(cur) if close(cur) => WriteOutcome::Verified,
+        Ok(cur) => WriteOutcome::Mismatched {
+            expected: target,
+            actual: cur,
+        },
+        Err(e) => {
+            debug!("failed to read back brightness for {}: {}", name, e);
+            WriteOutcome::Written
+        }
+    })
+}
+
 impl Device {
-    /// Ok if getting brightness was non-zero, otherwise Err with the error.
-    pub fn try_brightness(&mut self) -> Result<()> {
+    /// Ok with the maximum raw brightness if getting brightness was non-zero, otherwise
+    /// Err with the error.
+    pub fn try_brightness(&mut self) -> Result<u16> {
         // XXX: refresh?
-        self.inner.get_vcp_feature(0x10)?;
-        Ok(())
+        let cap = self.inner.get_vcp_feature(BRIGHTNESS)?;
+        self.max = Some(cap.maximum());
+        Ok(cap.maximum())
+    }
+
+    /// Sets how brightness percentages are converted to the device's raw values.
+    pub fn set_curve(&mut self, curve: BrightnessCurve) {
+        self.curve = curve;
     }
 
+    /// The brightness the device reads back after being set to b, as raw values
+    /// are whole numbers.
+    pub fn settled_brightness(&mut self, b: f64) -> Result<f64> {
+        let max = self.max_brightness()?;
+        Ok(self.curve.brightness(self.curve.raw(b, max), max))
+    }
+
+    /// Returns the device's parsed MCCS capabilities string. Reading it is slow, so
+    /// the result is cached after the first successful read.
+    pub fn capabilities(&mut self) -> Result<Capabilities> {
+        if let Some(caps) = &self.capabilities {
+            return Ok(caps.clone());
+        }
+
+        let raw = self
+            .inner
+            .capabilities_string()
+            .map_err(|e| format_err!("failed to read capabilities for {}: {}", self, e))?;
+        let raw = String::from_utf8_lossy(&raw);
+        trace!("capabilities string for {}: {}", self, raw);
+
+        let caps = Capabilities::parse(&raw)
+            .with_context(|| format!("failed to parse capabilities for {}", self))?;
+        self.capabilities = Some(caps.clone());
+        Ok(caps)
+    }
+
+    /// Returns the device's identity from its EDID or, if present, its override.
+    /// The result is cached after the first successful read.
     pub fn display_info(&mut self) -> Result<DeviceInfo> {
-        DeviceInfo::new(&mut self.inner)
+        if let Some(info) = &self.info {
+            return Ok(info.clone());
+        }
+
+        let (name, inner, conn) = (&self.name, &mut self.inner, &self.connector);
+        // The kernel's copy of the EDID is faster and more reliable than DDC.
+        let mut read = || match conn.as_ref().and_then(|c| Some((c, c.edid()?))) {
+            Some((conn, edid)) => DeviceInfo::parse(&edid).or_else(|e| {
+                warn!(
+                    "failed to parse EDID from connector {} for {}, reading over DDC: {}",
+                    conn, name, e
+                );
+                DeviceInfo::new(inner.device())
+            }),
+            None => DeviceInfo::new(inner.device()),
+        };
+
+        let mut info = match &self.edid_override {
+            Some(o) => {
+                debug!("using EDID override for {}", self.name);
+                o.apply(read)?
+            }
+            None => read()?,
+        };
+        info.set_location(&self.name, self.connector.as_ref());
+
+        self.info = Some(info.clone());
+        Ok(info)
+    }
+
+    /// Associates the device with the DRM connector its bus belongs to.
+    pub fn set_connector(&mut self, conn: Connector) {
+        self.info = None;
+        self.connector = Some(conn);
+    }
+
+    pub fn connector(&self) -> Option<&Connector> {
+        self.connector.as_ref()
+    }
+
+    /// Replaces what's read from the device's EDID with the override.
+    pub fn set_edid_override(&mut self, o: EdidOverride) {
+        self.info = None;
+        self.edid_override = Some(o);
+    }
+
+    /// Sets the delays between DDC/CI commands sent to the device.
+    pub fn set_timing(&mut self, timing: DdcTiming) {
+        if timing != self.inner.timing() {
+            debug!(
+                "pacing DDC/CI for {}: {}ms between commands, {}ms before replies",
+                self, timing.command_delay_ms, timing.reply_delay_ms
+            );
+        }
+        self.inner.set_timing(timing);
+    }
+
+    /// The path to the device's i2c bus, e.g. /dev/i2c-3.
+    pub fn bus(&self) -> &str {
+        &self.name
     }
 }
 
+/// adapter_name returns the i2c adapter name of a bus path, e.g. i2c-3 for /dev/i2c-3.
+pub(crate) fn adapter_name(path: &str) -> &str {
+    std::path::Path::new(path)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or(path)
+}
+
 impl std::fmt::Display for Device {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.name)
     }
 }
+
+#[test]
+fn test_write_brightness() {
+    /// A display which reads back what's written to it, rounded down to its steps.
+    struct Fake {
+        value: u16,
+        step: u16,
+        readable: bool,
+        writes: usize,
+    }
+
+    impl RawBrightness for Fake {
+        fn read(&mut self) -> Result<u16> {
+            match self.readable {
+                true => Ok(self.value),
+                false => Err(format_err!("no reply")),
+            }
+        }
+
+        fn write(&mut self, value: u16) -> Result<()> {
+            self.writes += 1;
+            self.value = value / self.step * self.step;
+            Ok(())
+        }
+    }
+    let fake = |value, step, readable| Fake {
+        value,
+        step,
+        readable,
+        writes: 0,
+    };
+
+    // already there, or close enough, so nothing's written
+    let mut dev = fake(49, 1, true);
+    assert_eq!(
+        WriteOutcome::Skipped,
+        write_brightness(&mut dev, "a", 50).unwrap()
+    );
+    assert_eq!(0, dev.writes);
+
+    let mut dev = fake(20, 1, true);
+    assert_eq!(
+        WriteOutcome::Verified,
+        write_brightness(&mut dev, "b", 50).unwrap()
+    );
+    assert_eq!((1, 50), (dev.writes, dev.value));
+
+    // steps coarser than the tolerance never read back the target
+    let mut dev = fake(20, 10, true);
+    assert_eq!(
+        WriteOutcome::Mismatched {
+            expected: 47,
+            actual: 40
+        },
+        write_brightness(&mut dev, "c", 47).unwrap()
+    );
+
+    let mut dev = fake(20, 1, false);
+    assert_eq!(
+        WriteOutcome::Written,
+        write_brightness(&mut dev, "d", 50).unwrap()
+    );
+    assert_eq!(1, dev.writes);
+}
@@ -1,83 +1,374 @@
+use std::collections::HashMap;
+
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 
-use crate::{logging::*, prelude::*};
+use super::device::adapter_name;
+use crate::{logging::*, prelude::*, types::*};
 
-#[derive(Default, Debug, Clone)]
+/// DeviceMatcher selects devices by their identity and location. Absent fields behave as
+/// wildcards, while present ones are all AND'd together.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceMatcher {
-    pub(crate) model: Option<Regex>,
-    pub(crate) mfg: Option<Regex>,
-    pub(crate) serial: Option<String>,
+    #[serde(default, with = "serde_regex", skip_serializing_if = "Option::is_none")]
+    pub model: Option<Regex>,
+    #[serde(
+        default,
+        rename = "manufacturer_id",
+        with = "serde_regex",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub mfg: Option<Regex>,
+    /// whole, case insensitive serial number.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial: Option<String>,
+    #[serde(default, with = "serde_regex", skip_serializing_if = "Option::is_none")]
+    pub serial_regex: Option<Regex>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_code: Option<u16>,
+    /// year of manufacture, or model year.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub year: Option<u16>,
+
+    /// hash of the raw EDID as shown by probe.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edid_hash: Option<String>,
+
+    /// i2c bus path, with or without /dev/.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bus: Option<String>,
+    /// DRM connector output name, e.g. DP-2, or sysfs name, e.g. card0-DP-2. Output
+    /// names shared by connectors on several cards only match with the card.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connector: Option<String>,
+
+    /// name of a matcher defined in the top-level aliases.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    #[serde(skip)]
+    pub(crate) resolved_alias: Option<Box<DeviceMatcher>>,
+
+    /// matches devices which the inner matcher does not.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not: Option<Box<DeviceMatcher>>,
+    /// matches devices which at least one of the inner matchers matches.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub any: Option<Vec<DeviceMatcher>>,
+    /// matches devices which every inner matcher matches.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub all: Vec<DeviceMatcher>,
 }
 
 impl DeviceMatcher {
-    /// Compares the current device matcher to the provided manufacturer, model,
-    /// and serial. Returns if there is a match.
+    /// Compares the current device matcher to the provided device. Returns if there is a match.
     pub fn matches(&self, info: &DeviceInfo) -> bool {
-        let mtches = match &self {
-            Self {
-                model: None,
-                mfg: None,
-                serial: None,
-            } => return true,
-            Self {
-                model: _,
-                mfg: _,
-                serial: Some(exact_serial),
-            } => return *exact_serial == info.serial,
-            Self {
-                model: Some(re_model),
-                mfg: None,
-                serial: None,
-            } => vec![re_model.find(&info.model)],
-            Self {
-                model: None,
-                mfg: Some(re_mfg),
-                serial: None,
-            } => vec![re_mfg.find(&info.manufacturer)],
-            Self {
-                model: Some(re_model),
-                mfg: Some(re_mfg),
-                serial: None,
-            } => vec![re_mfg.find(&info.manufacturer), re_model.find(&info.model)],
-        };
+        match self.explain(info) {
+            Ok(why) => {
+                debug!("{} matches: {}", info, why.join(", "));
+                true
+            }
+            Err(why) => {
+                debug!("{} does not match: {}", info, why.join(", "));
+                false
+            }
+        }
+    }
+
+    /// Checks every present field against the device. Returns Ok with a description of
+    /// each satisfied field if all of them are satisfied, otherwise Err with a description
+    /// of each unsatisfied one.
+    pub fn explain(&self, info: &DeviceInfo) -> std::result::Result<Vec<String>, Vec<String>> {
+        // one result per field, Ok if it's satisfied
+        let mut res = vec![];
+        let check =
+            |matched: bool, yes: String, no: String| if matched { Ok(yes) } else { Err(no) };
+
+        if let Some(re) = &self.model {
+            res.push(check(
+                re.is_match(&info.model),
+                format!("model {:?} matches {}", info.model, re),
+                format!("model {:?} does not match {}", info.model, re),
+            ));
+        }
+        if let Some(re) = &self.mfg {
+            res.push(check(
+                re.is_match(&info.manufacturer),
+                format!("manufacturer {:?} matches {}", info.manufacturer, re),
+                format!("manufacturer {:?} does not match {}", info.manufacturer, re),
+            ));
+        }
+        if let Some(serial) = &self.serial {
+            res.push(check(
+                serial.eq_ignore_ascii_case(&info.serial),
+                format!("serial is {:?}", serial),
+                format!("serial {:?} is not {:?}", info.serial, serial),
+            ));
+        }
+        if let Some(re) = &self.serial_regex {
+            res.push(check(
+                re.is_match(&info.serial),
+                format!("serial {:?} matches {}", info.serial, re),
+                format!("serial {:?} does not match {}", info.serial, re),
+            ));
+        }
+        if let Some(code) = self.product_code {
+            res.push(check(
+                code == info.product_code,
+                format!("product code is {:#06x}", code),
+                format!(
+                    "product code {:#06x} is not {:#06x}",
+                    info.product_code, code
+                ),
+            ));
+        }
+        if let Some(year) = self.year {
+            res.push(check(
+                year == info.manufacture_date.year,
+                format!("year is {}", year),
+                format!("year {} is not {}", info.manufacture_date.year, year),
+            ));
+        }
+        if let Some(hash) = &self.edid_hash {
+            res.push(check(
+                hash.eq_ignore_ascii_case(&info.edid_hash),
+                format!("EDID hash is {}", hash),
+                format!("EDID hash {} is not {}", info.edid_hash, hash),
+            ));
+        }
+        if let Some(bus) = &self.bus {
+            res.push(check(
+                adapter_name(bus) == adapter_name(&info.bus),
+                format!("bus is {}", bus),
+                format!("bus {} is not {}", info.bus, bus),
+            ));
+        }
+        if let Some(conn) = &self.connector {
+            // Either DP-2 or its sysfs name, card0-DP-2. The output name includes the
+            // card when several have a DP-2.
+            let on_conn = info.connector.as_ref() == Some(conn)
+                || info.connector_sysfs_name.as_ref() == Some(conn);
+            res.push(check(
+                on_conn,
+                format!("connector is {}", conn),
+                format!(
+                    "connector {} is not {}",
+                    info.connector.as_deref().unwrap_or("unknown"),
+                    conn
+                ),
+            ));
+        }
 
-        if mtches.is_empty() || mtches.iter().any(Option::is_none) {
-            debug!("{} does not match {}", info, self.internal_fmt());
-            trace!("matchers output: {:?}", mtches);
-            return false;
+        if let Some(alias) = &self.alias {
+            res.push(
+                match self.resolved_alias.as_ref().map(|m| m.explain(info)) {
+                    Some(Ok(_)) => Ok(format!("is {}", alias)),
+                    Some(Err(why)) => Err(format!("is not {} ({})", alias, why.join(", "))),
+                    None => Err(format!("alias {} was never resolved", alias)),
+                },
+            );
+        }
+        if let Some(not) = &self.not {
+            res.push(match not.explain(info) {
+                Ok(why) => Err(format!("excluded by not ({})", why.join(", "))),
+                Err(_) => Ok(format!("not {}", not.internal_fmt())),
+            });
+        }
+        if let Some(any) = &self.any {
+            res.push(match any.iter().find_map(|m| m.explain(info).ok()) {
+                Some(why) => Ok(format!("any of ({})", why.join(", "))),
+                None => Err(format!(
+                    "none of [{}]",
+                    any.iter()
+                        .map(DeviceMatcher::internal_fmt)
+                        .collect::<Vec<_>>()
+                        .join("; ")
+                )),
+            });
+        }
+        for m in &self.all {
+            match m.explain(info) {
+                Ok(why) => res.extend(why.into_iter().map(Ok)),
+                Err(why) => res.extend(why.into_iter().map(Err)),
+            }
         }
 
-        debug!("{} {}", info, self);
-        true
+        let (ok, failed): (Vec<_>, Vec<_>) = res.into_iter().partition(|r| r.is_ok());
+        if !failed.is_empty() {
+            return Err(failed.into_iter().filter_map(|r| r.err()).collect());
+        }
+        if ok.is_empty() {
+            return Ok(vec!["any device".to_owned()]);
+        }
+        Ok(ok.into_iter().filter_map(|r| r.ok()).collect())
+    }
+
+    /// Replaces alias references in this matcher and any nested ones with the
+    /// matchers they name. Aliases may not reference other aliases.
+    pub fn resolve_aliases(&mut self, aliases: &HashMap<String, DeviceMatcher>) -> Result<()> {
+        if let Some(name) = &self.alias {
+            let alias = aliases
+                .get(name)
+                .ok_or_else(|| format_err!("alias {:?} is not defined", name))?;
+            if alias.has_alias() {
+                return Err(format_err!(
+                    "alias {:?} may not reference another alias",
+                    name
+                ));
+            }
+            self.resolved_alias = Some(Box::new(alias.clone()));
+        }
+
+        if let Some(not) = &mut self.not {
+            not.resolve_aliases(aliases)?;
+        }
+        for m in self.any.iter_mut().flatten().chain(self.all.iter_mut()) {
+            m.resolve_aliases(aliases)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the matcher is meant to select one particular unit of display rather
+    /// than a model or family of them.
+    pub fn is_unit_specific(&self) -> bool {
+        self.serial.is_some()
+            || self.serial_regex.is_some()
+            || self.edid_hash.is_some()
+            || self
+                .resolved_alias
+                .as_ref()
+                .map(|m| m.is_unit_specific())
+                .unwrap_or(false)
+            || self.all.iter().any(DeviceMatcher::is_unit_specific)
+    }
+
+    /// Whether every device the other matcher matches is also matched by this one, so
+    /// a rule using other is unreachable after a rule using this. Only matchers whose
+    /// fields are a subset of the other's, or which are identical, are recognized.
+    pub fn covers(&self, other: &DeviceMatcher) -> bool {
+        if self.alias.is_some() || self.not.is_some() || self.any.is_some() || !self.all.is_empty()
+        {
+            return self.same_as(other);
+        }
+        self.fields_cover(other, false)
+    }
+
+    /// Compares each field present in this matcher with the other's. If exact, fields
+    /// absent here must be absent there too.
+    fn fields_cover(&self, other: &DeviceMatcher, exact: bool) -> bool {
+        fn same<T, F: Fn(&T, &T) -> bool>(
+            mine: &Option<T>,
+            theirs: &Option<T>,
+            exact: bool,
+            eq: F,
+        ) -> bool {
+            match (mine, theirs) {
+                (None, None) => true,
+                (None, Some(_)) => !exact,
+                (Some(a), Some(b)) => eq(a, b),
+                (Some(_), None) => false,
+            }
+        }
+        let re = |a: &Regex, b: &Regex| a.as_str() == b.as_str();
+        let text = |a: &String, b: &String| a.eq_ignore_ascii_case(b);
+
+        same(&self.model, &other.model, exact, re)
+            && same(&self.mfg, &other.mfg, exact, re)
+            && same(&self.serial, &other.serial, exact, text)
+            && same(&self.serial_regex, &other.serial_regex, exact, re)
+            && same(&self.product_code, &other.product_code, exact, |a, b| a == b)
+            && same(&self.year, &other.year, exact, |a, b| a == b)
+            && same(&self.edid_hash, &other.edid_hash, exact, text)
+            && same(&self.bus, &other.bus, exact, |a, b| {
+                adapter_name(a) == adapter_name(b)
+            })
+            // DP-2 and card0-DP-2 are only the same connector if no other card has a
+            // DP-2, which isn't known until displays are found.
+            && same(&self.connector, &other.connector, exact, |a, b| a == b)
+    }
+
+    /// Whether the matchers are the same, field by field and combinator by combinator.
+    fn same_as(&self, other: &DeviceMatcher) -> bool {
+        let all_same = |mine: &[DeviceMatcher], theirs: &[DeviceMatcher]| {
+            mine.len() == theirs.len() && mine.iter().zip(theirs).all(|(a, b)| a.same_as(b))
+        };
+
+        self.fields_cover(other, true)
+            && self.alias == other.alias
+            && match (&self.not, &other.not) {
+                (None, None) => true,
+                (Some(a), Some(b)) => a.same_as(b),
+                _ => false,
+            }
+            && match (&self.any, &other.any) {
+                (None, None) => true,
+                (Some(a), Some(b)) => all_same(a, b),
+                _ => false,
+            }
+            && all_same(&self.all, &other.all)
+    }
+
+    fn has_alias(&self) -> bool {
+        self.alias.is_some()
+            || self.not.as_ref().map(|m| m.has_alias()).unwrap_or(false)
+            || self
+                .any
+                .iter()
+                .flatten()
+                .chain(self.all.iter())
+                .any(DeviceMatcher::has_alias)
     }
 
     fn internal_fmt(&self) -> String {
-        match &self {
-            Self {
-                model: None,
-                mfg: None,
-                serial: None,
-            } => "any device".to_owned(),
-            Self {
-                model: _,
-                mfg: _,
-                serial: Some(serial),
-            } => format!("serial {}", serial),
-            Self {
-                model: Some(model),
-                mfg: None,
-                serial: None,
-            } => format!("model {}", model),
-            Self {
-                model: None,
-                mfg: Some(mfg),
-                serial: None,
-            } => format!("manufacturer {}", mfg),
-            Self {
-                model: Some(model),
-                mfg: Some(mfg),
-                serial: None,
-            } => format!("model {} and manufacturer {}", model, mfg),
+        let mut parts = vec![];
+        if let Some(model) = &self.model {
+            parts.push(format!("model {}", model));
+        }
+        if let Some(mfg) = &self.mfg {
+            parts.push(format!("manufacturer {}", mfg));
+        }
+        if let Some(serial) = &self.serial {
+            parts.push(format!("serial {}", serial));
+        }
+        if let Some(serial) = &self.serial_regex {
+            parts.push(format!("serial {}", serial));
+        }
+        if let Some(code) = self.product_code {
+            parts.push(format!("product code {:#06x}", code));
+        }
+        if let Some(year) = self.year {
+            parts.push(format!("year {}", year));
+        }
+        if let Some(hash) = &self.edid_hash {
+            parts.push(format!("EDID hash {}", hash));
+        }
+        if let Some(bus) = &self.bus {
+            parts.push(format!("bus {}", bus));
+        }
+        if let Some(conn) = &self.connector {
+            parts.push(format!("connector {}", conn));
+        }
+        if let Some(alias) = &self.alias {
+            parts.push(format!("alias {}", alias));
+        }
+        if let Some(not) = &self.not {
+            parts.push(format!("not ({})", not.internal_fmt()));
+        }
+        if let Some(any) = &self.any {
+            parts.push(format!(
+                "any of ({})",
+                any.iter()
+                    .map(DeviceMatcher::internal_fmt)
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ));
+        }
+        parts.extend(self.all.iter().map(|m| format!("({})", m.internal_fmt())));
+
+        if parts.is_empty() {
+            "any device".to_owned()
+        } else {
+            parts.join(" and ")
         }
     }
 }
@@ -87,3 +378,66 @@ impl std::fmt::Display for DeviceMatcher {
         write!(f, "matches {}", self.internal_fmt())
     }
 }
+
+#[test]
+fn test_matcher_combinators() {
+    let info = DeviceInfo {
+        manufacturer: "DEL".to_owned(),
+        model: "DELL U2415".to_owned(),
+        serial: "ABCD1234".to_owned(),
+        bus: "/dev/i2c-3".to_owned(),
+        connector: Some("DP-2".to_owned()),
+        connector_sysfs_name: Some("card0-DP-2".to_owned()),
+        ..DeviceInfo::default()
+    };
+    let parse = |yaml: &str| serde_yaml::from_str::<DeviceMatcher>(yaml).unwrap();
+
+    assert!(parse("{}").matches(&info));
+    assert!(parse("{model: U2415, serial: abcd1234, bus: i2c-3}").matches(&info));
+    // serial no longer overrides other fields
+    assert!(!parse("{model: U2720Q, serial: ABCD1234}").matches(&info));
+    assert!(parse("{connector: card0-DP-2}").matches(&info));
+    assert!(!parse("{connector: card1-DP-2}").matches(&info));
+    // with a DP-2 on another card, the card must be given
+    let shared = DeviceInfo {
+        connector: Some("card0-DP-2".to_owned()),
+        ..info.clone()
+    };
+    assert!(!parse("{connector: DP-2}").matches(&shared));
+    assert!(parse("{connector: card0-DP-2}").matches(&shared));
+    assert!(!parse("{not: {manufacturer_id: DEL}}").matches(&info));
+    assert!(parse("{any: [{model: U2720Q}, {serial_regex: ^ABCD}]}").matches(&info));
+    assert!(!parse("{all: [{model: U2415}, {connector: DP-1}]}").matches(&info));
+
+    let mut aliased = parse("{alias: left, not: {alias: right}}");
+    let aliases =
+        serde_yaml::from_str("{left: {bus: /dev/i2c-3}, right: {bus: /dev/i2c-4}}").unwrap();
+    aliased.resolve_aliases(&aliases).unwrap();
+    assert!(aliased.matches(&info));
+    assert_eq!(
+        Err(vec!["bus /dev/i2c-3 is not /dev/i2c-4".to_owned()]),
+        parse("{bus: /dev/i2c-4}").explain(&info)
+    );
+}
+
+#[test]
+fn test_matcher_covers() {
+    let parse = |yaml: &str| serde_yaml::from_str::<DeviceMatcher>(yaml).unwrap();
+
+    assert!(parse("{}").covers(&parse("{model: U2415}")));
+    assert!(parse("{model: U2415}").covers(&parse("{model: U2415, serial: abc}")));
+    assert!(parse("{bus: i2c-3}").covers(&parse("{bus: /dev/i2c-3, model: U2415}")));
+    assert!(parse("{not: {model: U2415}}").covers(&parse("{not: {model: U2415}}")));
+
+    assert!(!parse("{model: U2415}").covers(&parse("{}")));
+    assert!(!parse("{model: U2415}").covers(&parse("{model: U2415Q}")));
+    assert!(!parse("{not: {model: U2415}}").covers(&parse("{model: P2415}")));
+    // formatted alike, but not the same
+    assert!(!parse("{serial: ABC}").covers(&parse("{serial_regex: ABC}")));
+    assert!(!parse("{not: {serial: ABC}}").covers(&parse("{not: {serial_regex: ABC}}")));
+    assert!(!parse("{not: {model: U2415}}").covers(&parse("{not: {model: U2415, bus: i2c-3}}")));
+    // another card may have a DP-2
+    assert!(!parse("{connector: DP-2}").covers(&parse("{connector: card1-DP-2}")));
+    assert!(parse("{any: [{model: U2415}, {bus: i2c-3}]}")
+        .covers(&parse("{any: [{model: U2415}, {bus: /dev/i2c-3}]}")));
+}
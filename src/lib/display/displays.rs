@@ -1,26 +1,110 @@
 use std::convert::TryFrom;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-use crate::{config::DeviceConfig, logging::*, prelude::*, types::*};
+use crate::{
+    config::{DeviceConfig, DiscoveryOpts},
+    logging::*,
+    prelude::*,
+    types::*,
+};
 
-/// Display is a i2c device paired with its configuration.
+/// Display is a i2c device paired with its configuration. The device is shared so
+/// that commands to it may run off the async runtime, one at a time.
 pub struct Display<'a> {
-    device: Device,
+    name: String,
+    identity: String,
+    bus: String,
+    device: Arc<Mutex<Device>>,
     cfg: &'a DeviceConfig,
+    /// the configured day and night brightness, relative to the device's maximum.
+    brightness: (f64, f64),
 }
 
 pub struct Displays<'a> {
+    found: Vec<Found>,
     displays: Vec<Display<'a>>,
 }
 
+/// A compatible device found during discovery, whether or not a rule claims it.
+struct Found {
+    name: String,
+    identity: String,
+    device: Arc<Mutex<Device>>,
+    info: DeviceInfo,
+    /// the device's maximum raw brightness.
+    max: u16,
+}
+
 impl<'a> Display<'a> {
+    /// Locks the device, setting its curve to the claiming rule's.
+    fn device(&self) -> Result<MutexGuard<'_, Device>> {
+        let mut device = self
+            .device
+            .lock()
+            .map_err(|_| format_err!("{} is unusable after a command to it panicked", self))?;
+        device.set_curve(self.cfg.curve.clone());
+        Ok(device)
+    }
+
     pub fn display_info(&mut self) -> Result<DeviceInfo> {
-        self.device.display_info()
+        self.device()?.display_info()
+    }
+
+    pub fn capabilities(&mut self) -> Result<Capabilities> {
+        self.device()?.capabilities()
+    }
+
+    /// The rule which claimed the display.
+    pub fn config(&self) -> &'a DeviceConfig {
+        self.cfg
+    }
+
+    /// Identifies the display across restarts. It's the EDID identity, along with
+    /// the connector or bus if other displays share it.
+    pub fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// Path of the i2c bus the display is on.
+    pub fn bus(&self) -> &str {
+        &self.bus
+    }
+
+    /// The configured brightness for the time of day, relative to the device's maximum.
+    pub fn target_brightness(&self, is_daytime: bool) -> f64 {
+        if is_daytime {
+            self.brightness.0
+        } else {
+            self.brightness.1
+        }
+    }
+
+    /// Runs f with the device on the blocking thread pool so that other displays may
+    /// be controlled in parallel.
+    pub async fn with_device<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Device) -> Result<T> + Send + 'static,
+    {
+        let device = self.device.clone();
+        let name = self.name.clone();
+        let curve = self.cfg.curve.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut device = device
+                .lock()
+                .map_err(|_| format_err!("{} is unusable after a command to it panicked", name))?;
+            device.set_curve(curve);
+            f(&mut device)
+        })
+        .await?
     }
 }
 
 impl<'a> std::fmt::Display for Display<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.device.fmt(f)
+        // Not the device's, as its lock may be held while a command runs.
+        write!(f, "{}", self.name)
     }
 }
 
@@ -28,19 +112,76 @@ impl<'a> Displays<'a> {
     /// Create a new set of displays from device configs, matching up
     /// displays to their appropriate configuration. Unmatched displays
     /// will be discarded.
-    pub fn new<C: IntoIterator<Item = &'a DeviceConfig>>(cfgs: C) -> Result<Self> {
-        let raw_devs = ddc_i2c::I2cDeviceEnumerator::new()?
+    pub fn new<C: IntoIterator<Item = &'a DeviceConfig>>(
+        cfgs: C,
+        discovery: &DiscoveryOpts,
+    ) -> Result<Self> {
+        let raw_devs = super::bus::buses(&discovery.buses)?
+            .into_iter()
+            .filter_map(|bus| match bus.open() {
+                Ok(dev) => Some(dev),
+                Err(e) => {
+                    debug!("skipping {} ({}): {}", bus.path, bus.adapter, e);
+                    None
+                }
+            })
             .map(TryFrom::try_from)
             .collect::<Result<Vec<Device>>>()?;
 
+        // A broken EDID shouldn't prevent managing the device or any others: it is
+        // still claimed by rules which don't depend on EDID fields.
+        let mut conns = super::drm::connectors();
+        let raw_devs = raw_devs
+            .into_iter()
+            .map(|mut dev| {
+                if let Some(conn) = conns.remove(super::adapter_name(dev.bus())) {
+                    debug!("{} is connected to {}", dev, conn.sysfs_name);
+                    dev.set_connector(conn);
+                }
+
+                if let Some(o) = discovery
+                    .edid_overrides
+                    .iter()
+                    .find(|o| o.applies_to(dev.bus(), dev.connector()))
+                {
+                    dev.set_edid_override(o.clone());
+                }
+
+                let info = dev.display_info().unwrap_or_else(|e| {
+                    warn!("failed to read display info for {}: {}", dev, e);
+                    DeviceInfo::without_edid(dev.bus(), dev.connector())
+                });
+                // Before any DDC/CI commands, as slow displays may not answer otherwise.
+                dev.set_timing(discovery.ddc.timing_for(&info));
+
+                (dev, info)
+            })
+            .collect::<Vec<_>>();
+
         let mut devs = vec![];
+        let mut infos = vec![];
         let mut unavail_devs = vec![];
 
-        for mut dev in raw_devs {
+        for (mut dev, info) in raw_devs {
+            // Displays which publish their capabilities are trusted to list what they
+            // support, others are probed by trial.
+            match dev.capabilities() {
+                Ok(caps) if !caps.supports(super::BRIGHTNESS) => {
+                    unavail_devs.push((
+                        dev,
+                        format_err!("brightness control isn't listed in its capabilities"),
+                    ));
+                    continue;
+                }
+                Ok(caps) => debug!("{} capabilities: {}", dev, caps),
+                Err(e) => debug!("{}, probing brightness support directly", e),
+            }
+
             match dev.try_brightness() {
-                Ok(_) => {
+                Ok(max) => {
                     trace!("found device {}", dev);
-                    devs.push(dev)
+                    devs.push((dev, max));
+                    infos.push(info);
                 }
                 Err(e) => unavail_devs.push((dev, e)),
             }
@@ -59,22 +200,107 @@ impl<'a> Displays<'a> {
             };
         }
 
-        // Pair discovered devices to matching configs.
-        let mut displays = Vec::with_capacity(devs.len());
+        for group in shared_identities(&infos).iter() {
+            warn!(
+                "{} displays on {} share the identity {}; match them by bus or connector to configure them separately",
+                group.len(),
+                group.iter().map(|i| i.bus.as_str()).collect::<Vec<_>>().join(", "),
+                group[0].identity(),
+            );
+        }
+
+        let found = devs
+            .into_iter()
+            .zip(infos.iter())
+            .zip(identities(&infos))
+            .map(|(((dev, max), info), identity)| Found {
+                name: dev.to_string(),
+                identity,
+                device: Arc::new(Mutex::new(dev)),
+                info: info.clone(),
+                max,
+            })
+            .collect();
+
+        let mut displays = Displays {
+            found,
+            displays: vec![],
+        };
+        displays.assign(cfgs);
+        Ok(displays)
+    }
+
+    /// Pairs discovered devices with the first of the configs which matches them,
+    /// replacing any previous pairing. Unmatched devices are set aside until the
+    /// next call.
+    pub fn assign<C: IntoIterator<Item = &'a DeviceConfig>>(&mut self, cfgs: C) {
         let cfgs: Vec<_> = cfgs.into_iter().collect();
-        for mut dev in devs {
-            // earlier configs get priority
-            for cfg in &cfgs {
-                let info = dev.display_info()?;
+        let mut claims = vec![vec![]; cfgs.len()];
+        self.displays.clear();
 
-                if cfg.matcher.matches(&info) {
-                    displays.push(Display { device: dev, cfg });
-                    break;
+        for found in self.found.iter() {
+            // earlier configs get priority
+            match cfgs.iter().enumerate().find_map(|(i, cfg)| {
+                match cfg.matcher.explain(&found.info) {
+                    Ok(why) => Some((i, cfg, why)),
+                    Err(why) => {
+                        debug!(
+                            "rule {} does not claim {}: {}",
+                            i + 1,
+                            found.name,
+                            why.join(", ")
+                        );
+                        None
+                    }
+                }
+            }) {
+                Some((i, cfg, why)) => {
+                    debug!("rule {} claims {}: {}", i + 1, found.name, why.join(", "));
+                    claims[i].push(&found.info);
+                    let brightness =
+                        cfg.brightness_for(true, &found.info, found.max)
+                            .and_then(|day| {
+                                Ok((day, cfg.brightness_for(false, &found.info, found.max)?))
+                            });
+                    match brightness {
+                        Ok(brightness) => self.displays.push(Display {
+                            name: found.name.clone(),
+                            identity: found.identity.clone(),
+                            bus: found.name.clone(),
+                            device: found.device.clone(),
+                            cfg,
+                            brightness,
+                        }),
+                        Err(e) => warn!(
+                            "rule {} claims {}, but it's left alone: {}",
+                            i + 1,
+                            found.name,
+                            e
+                        ),
+                    }
                 }
+                None => info!(
+                    "{} ({}) is not matched by any rule, ignoring it",
+                    found.name, found.info
+                ),
             }
         }
 
-        Ok(Displays { displays })
+        for (i, claimed) in claims.iter().enumerate() {
+            if claimed.len() > 1 && cfgs[i].matcher.is_unit_specific() {
+                warn!(
+                    "rule {} {} and looks meant for a single display, but it claims {}: {}",
+                    i + 1,
+                    cfgs[i].matcher,
+                    claimed.len(),
+                    claimed
+                        .iter()
+                        .map(|i| format!("{} on {}", i, i.bus))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+            }
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -94,17 +320,62 @@ impl<'a> Displays<'a> {
     }
 }
 
-pub trait BrightnessOps {
-    /// Idempotently update brightness of display based on config.
-    fn update_brightness(&mut self, is_daytime: bool) -> Result<()>;
+/// Groups physically distinct displays which report the same identity. Displays whose
+/// identity is unique are omitted.
+pub fn shared_identities(infos: &[DeviceInfo]) -> Vec<Vec<&DeviceInfo>> {
+    let mut groups: Vec<Vec<&DeviceInfo>> = vec![];
+    for info in infos {
+        match groups
+            .iter_mut()
+            .find(|g| g[0].identity() == info.identity())
+        {
+            Some(group) => group.push(info),
+            None => groups.push(vec![info]),
+        }
+    }
+
+    groups.retain(|g| g.len() > 1);
+    groups
 }
 
-impl<'a> BrightnessOps for Display<'a> {
-    fn update_brightness(&mut self, is_daytime: bool) -> Result<()> {
-        self.device.set_brightness(if is_daytime {
-            self.cfg.day_brightness
-        } else {
-            self.cfg.night_brightness
+/// Identities which tell each display apart across restarts. Displays which share an
+/// identity are further identified by their connector, or bus without one.
+fn identities(infos: &[DeviceInfo]) -> Vec<String> {
+    let shared = shared_identities(infos);
+    infos
+        .iter()
+        .map(|info| {
+            if shared.iter().any(|g| g.iter().any(|s| s.bus == info.bus)) {
+                format!(
+                    "{} on {}",
+                    info.identity(),
+                    info.connector.as_ref().unwrap_or(&info.bus)
+                )
+            } else {
+                info.identity()
+            }
         })
-    }
+        .collect()
+}
+
+#[test]
+fn test_unreadable_edids() {
+    let infos = vec![
+        DeviceInfo::without_edid(
+            "/dev/i2c-3",
+            super::Connector::new("card0-DP-1", Default::default()).as_ref(),
+        ),
+        DeviceInfo::without_edid("/dev/i2c-4", None),
+    ];
+
+    // displays without an EDID look alike, so they're told apart by where they are
+    assert_eq!(1, shared_identities(&infos).len());
+    let ids = identities(&infos);
+    assert!(ids[0].ends_with(" on DP-1"), "{}", ids[0]);
+    assert!(ids[1].ends_with(" on /dev/i2c-4"), "{}", ids[1]);
+
+    let parse = |yaml: &str| serde_yaml::from_str::<DeviceMatcher>(yaml).unwrap();
+    assert!(parse("{connector: DP-1}").matches(&infos[0]));
+    assert!(parse("{bus: i2c-4}").matches(&infos[1]));
+    assert!(!parse("{bus: i2c-4}").matches(&infos[0]));
 }
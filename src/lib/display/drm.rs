@@ -0,0 +1,139 @@
+use std::collections::HashMap;
+use std::fs::{read, read_dir, read_link};
+use std::path::{Path, PathBuf};
+
+use crate::{logging::*, types::*};
+
+const DRM_ROOT: &str = "/sys/class/drm";
+
+/// Connector is a DRM output, such as a DisplayPort or HDMI port on a GPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connector {
+    /// Output name as shown by xrandr and compositors, e.g. DP-2.
+    pub name: String,
+    /// Name of the connector in sysfs, e.g. card0-DP-2.
+    pub sysfs_name: String,
+    path: PathBuf,
+}
+
+impl Connector {
+    /// The connector with the sysfs name, e.g. card0-DP-2, whose directory is at
+    /// path. None for bare cards and render nodes, which have no dash.
+    pub(crate) fn new(sysfs_name: &str, path: PathBuf) -> Option<Self> {
+        match sysfs_name.split_once('-') {
+            Some((card, name)) if card.starts_with("card") => Some(Self {
+                name: name.to_owned(),
+                sysfs_name: sysfs_name.to_owned(),
+                path,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether the passed name refers to this connector, either by output name or
+    /// sysfs name.
+    pub fn is(&self, name: &str) -> bool {
+        self.name == name || self.sysfs_name == name
+    }
+
+    /// Returns the EDID the kernel read for this connector, if there's a display
+    /// connected and the driver exposes it.
+    pub fn edid(&self) -> Option<Vec<u8>> {
+        match read(self.path.join("edid")) {
+            Ok(edid) if !edid.is_empty() => Some(edid),
+            Ok(_) => None,
+            Err(e) => {
+                trace!("failed to read EDID for connector {}: {}", self.name, e);
+                None
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for Connector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+/// Maps i2c adapter names (e.g. i2c-5) to the DRM connector whose DDC lines they're
+/// attached to. Connectors without a ddc link are skipped.
+pub fn connectors() -> HashMap<String, Connector> {
+    connectors_in(Path::new(DRM_ROOT)).unwrap_or_else(|e| {
+        debug!("failed to read DRM connectors from {}: {}", DRM_ROOT, e);
+        HashMap::new()
+    })
+}
+
+fn connectors_in(root: &Path) -> Result<HashMap<String, Connector>> {
+    let mut conns = HashMap::new();
+
+    for entry in read_dir(root)? {
+        let path = entry?.path();
+        let conn = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => match Connector::new(n, path.clone()) {
+                Some(conn) => conn,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let bus = match read_link(path.join("ddc")) {
+            Ok(link) => match link.file_name().and_then(|n| n.to_str()) {
+                Some(bus) => bus.to_owned(),
+                None => continue,
+            },
+            Err(_) => continue,
+        };
+
+        trace!("found DRM connector {} on {}", conn.sysfs_name, bus);
+        conns.insert(bus, conn);
+    }
+
+    // Cards number their connectors separately, so with several of them, output names
+    // may only be unique along with the card's.
+    let names = conns.values().map(|c| c.name.clone()).collect::<Vec<_>>();
+    for conn in conns.values_mut() {
+        if names.iter().filter(|n| **n == conn.name).count() > 1 {
+            conn.name = conn.sysfs_name.clone();
+        }
+    }
+
+    Ok(conns)
+}
+
+#[test]
+fn test_connectors_in() {
+    let root = std::env::temp_dir().join(format!("dlux-drm-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    for dir in &[
+        "card0",
+        "card0-DP-2",
+        "card0-HDMI-A-1",
+        "card1-DP-1",
+        "card1-DP-2",
+    ] {
+        std::fs::create_dir_all(root.join(dir)).unwrap();
+    }
+    std::os::unix::fs::symlink("../../../i2c-5", root.join("card0-DP-2/ddc")).unwrap();
+    std::fs::write(root.join("card0-DP-2/edid"), b"").unwrap();
+    std::os::unix::fs::symlink("../../../i2c-7", root.join("card1-DP-1/ddc")).unwrap();
+
+    let conns = connectors_in(&root).unwrap();
+    let edid = conns.get("i2c-5").and_then(Connector::edid);
+    assert_eq!(2, conns.len());
+    assert_eq!("DP-1", conns["i2c-7"].name);
+
+    // another card's DP-2 makes the name ambiguous
+    std::os::unix::fs::symlink("../../../i2c-8", root.join("card1-DP-2/ddc")).unwrap();
+    let shared = connectors_in(&root).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+
+    let conn = &conns["i2c-5"];
+    assert_eq!("DP-2", conn.name);
+    assert!(conn.is("card0-DP-2"));
+    assert_eq!(None, edid);
+    assert_eq!("card0-DP-2", shared["i2c-5"].name);
+    assert_eq!("card1-DP-2", shared["i2c-8"].name);
+    assert!(!shared["i2c-8"].is("DP-2"));
+}
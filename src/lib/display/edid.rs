@@ -1,31 +1,136 @@
-use ddc::Edid;
+use ddc::Eddc;
 
-use crate::types::*;
+use super::Connector;
+use crate::{logging::*, types::*};
+
+/// Every EDID block, base or extension, is 128 bytes.
+const BLOCK_LEN: usize = 128;
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
 
 #[derive(Debug, Default, Clone)]
 pub struct DeviceInfo {
+    /// Three letter PNP manufacturer id, e.g. DEL.
     pub manufacturer: String,
+    /// Display product name from the 0xFC descriptor.
     pub model: String,
+    /// Display serial number from the 0xFF descriptor.
     pub serial: String,
+    /// Unspecified alphanumeric text from 0xFE descriptors. Panels often
+    /// put their part number here.
+    pub alphanumeric: Vec<String>,
+
+    /// Manufacturer's product code, bytes 10-11.
+    pub product_code: u16,
+    /// Numeric serial number, bytes 12-15. Zero when unused.
+    pub serial_number: u32,
+    pub manufacture_date: ManufactureDate,
+    pub version: EdidVersion,
+    /// Physical display size, absent for projectors and when only an
+    /// aspect ratio is provided.
+    pub physical_size: Option<PhysicalSize>,
+    /// Extension blocks with valid checksums, in order.
+    pub extensions: Vec<Extension>,
+    /// Maximum full screen luminance in cd/m² advertised in an extension block, if any.
+    pub max_luminance: Option<f64>,
+
+    /// Hex FNV-1a hash of the raw EDID, which may tell apart displays that share a
+    /// model and serial. Empty if there was no EDID.
+    pub edid_hash: String,
+
+    /// Path of the i2c bus the device was found on. Not part of the EDID.
+    pub bus: String,
+    /// DRM connector output name, e.g. DP-2, if the bus could be correlated
+    /// with one. Not part of the EDID.
+    pub connector: Option<String>,
+    /// sysfs name of the connector, e.g. card0-DP-2.
+    pub connector_sysfs_name: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ManufactureDate {
+    /// Week of manufacture, 1-54, if the manufacturer specified one.
+    pub week: Option<u8>,
+    pub year: u16,
+    /// Set when year is a model year rather than the year of manufacture.
+    pub model_year: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EdidVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalSize {
+    pub width_cm: u8,
+    pub height_cm: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Extension {
+    /// CTA-861 (a.k.a. CEA-861) timing extension.
+    Cta861 {
+        revision: u8,
+    },
+    /// VESA DisplayID embedded in an EDID extension.
+    DisplayId {
+        version: u8,
+    },
+    /// Lists the tags of following extensions. Only present on EDIDs with
+    /// more than one extension.
+    BlockMap,
+    Other {
+        tag: u8,
+    },
 }
 
 impl DeviceInfo {
-    pub fn new<F: std::error::Error, T: Edid<EdidError = F>>(d: &mut T) -> Result<DeviceInfo> {
-        let mut edid = vec![0; 128];
-        match d
-            .read_edid(0, &mut edid)
-            .map_err(|e| format_err!("error reading device EDID: {}", e))
-        {
-            Err(e) => Err(format_err!("error reading device EDID: {}", e)),
-            Ok(128) => Ok(()),
-            Ok(size) => Err(format_err!(
-                "read insufficient data from device EDID: got {} bytes, wanted {}",
-                size,
-                edid.capacity()
-            )),
-        }?;
+    /// Reads the base EDID block and any extension blocks from the device and parses them.
+    /// Extensions past the first 256 bytes are read with E-DDC segment addressing.
+    pub fn new<F: std::error::Error, T: Eddc<EdidError = F>>(d: &mut T) -> Result<DeviceInfo> {
+        let mut edid = vec![0; BLOCK_LEN];
+        read_block(d, 0, &mut edid)?;
+        verify_base(&edid)?;
+
+        for i in 1..=(edid[126] as usize) {
+            let mut block = vec![0; BLOCK_LEN];
+            if let Err(e) = read_block(d, i, &mut block) {
+                warn!("failed to read EDID extension block {}: {}", i, e);
+                break;
+            }
+            edid.extend(block);
+        }
+
+        DeviceInfo::parse(&edid)
+    }
+
+    /// Parses a complete EDID, such as one read from a file or sysfs. The base block
+    /// must be present and have a valid checksum. Extension blocks which are missing or
+    /// fail their checksum are skipped with a warning.
+    pub fn parse(edid: &[u8]) -> Result<DeviceInfo> {
+        verify_base(edid)?;
+
+        let mut info = DeviceInfo {
+            manufacturer: read_mfg_id(&edid[8..=9])?,
+            product_code: u16::from_le_bytes([edid[10], edid[11]]),
+            serial_number: u32::from_le_bytes([edid[12], edid[13], edid[14], edid[15]]),
+            manufacture_date: read_manufacture_date(edid[16], edid[17]),
+            version: EdidVersion {
+                major: edid[18],
+                minor: edid[19],
+            },
+            physical_size: match (edid[21], edid[22]) {
+                (0, _) | (_, 0) => None,
+                (width_cm, height_cm) => Some(PhysicalSize {
+                    width_cm,
+                    height_cm,
+                }),
+            },
+            edid_hash: format!("{:016x}", fnv1a(edid)),
+            ..DeviceInfo::default()
+        };
 
-        let mut info = DeviceInfo::default();
         let descrs = vec![
             &edid[54..72],
             &edid[72..90],
@@ -37,13 +142,129 @@ impl DeviceInfo {
             match read_descriptor(descr)? {
                 DispDescr::Serial(srl) => info.serial = srl,
                 DispDescr::Model(model) => info.model = model,
+                DispDescr::Alphanumeric(text) => info.alphanumeric.push(text),
                 _ => (),
             }
         }
 
-        info.manufacturer = read_mfg_id(&edid[8..=9])?;
+        let ext_count = edid[126] as usize;
+        for (i, block) in edid[BLOCK_LEN..]
+            .chunks(BLOCK_LEN)
+            .take(ext_count)
+            .enumerate()
+        {
+            if block.len() != BLOCK_LEN {
+                warn!("EDID extension block {} is truncated", i + 1);
+                break;
+            }
+            if checksum(block) != 0 {
+                warn!(
+                    "EDID extension block {} has an invalid checksum, skipping",
+                    i + 1
+                );
+                continue;
+            }
+
+            info.extensions.push(read_extension(block));
+            if info.max_luminance.is_none() {
+                info.max_luminance = read_max_luminance(block);
+            }
+        }
+
+        let avail = edid.len() / BLOCK_LEN - 1;
+        if avail < ext_count {
+            warn!(
+                "EDID advertises {} extension blocks but only {} were available",
+                ext_count, avail
+            );
+        }
+
         Ok(info)
     }
+
+    /// Info for a device whose EDID couldn't be read, so that it may still be told
+    /// apart and claimed by where it's connected.
+    pub fn without_edid(bus: &str, connector: Option<&Connector>) -> Self {
+        let mut info = DeviceInfo::default();
+        info.set_location(bus, connector);
+        info
+    }
+
+    /// Sets where the device was found, which isn't part of the EDID.
+    pub(crate) fn set_location(&mut self, bus: &str, connector: Option<&Connector>) {
+        self.bus = bus.to_owned();
+        self.connector = connector.map(|c| c.name.clone());
+        self.connector_sysfs_name = connector.map(|c| c.sysfs_name.clone());
+    }
+
+    /// Returns a string identifying the model and unit of display. Physically distinct
+    /// displays with the same identity can only be told apart by their location.
+    pub fn identity(&self) -> String {
+        format!(
+            "{} {} {:#06x} (SN: {:?}, {:#010x})",
+            self.manufacturer, self.model, self.product_code, self.serial, self.serial_number
+        )
+    }
+}
+
+/// fnv1a is the 64-bit FNV-1a hash. It's used instead of std's hasher so hashes are
+/// stable across releases and may be put into configs.
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// read_block reads a 128 byte EDID block. The first two blocks are addressable with
+/// plain DDC, while later ones require the E-DDC segment pointer.
+fn read_block<F: std::error::Error, T: Eddc<EdidError = F>>(
+    d: &mut T,
+    block: usize,
+    buf: &mut [u8],
+) -> Result<()> {
+    let offset = ((block % 2) * BLOCK_LEN) as u8;
+    let res = match block / 2 {
+        0 => d.read_edid(offset, buf),
+        segment => d.read_eddc_edid(segment as u8, offset, buf),
+    };
+
+    match res {
+        Err(e) => Err(format_err!("error reading device EDID: {}", e)),
+        Ok(BLOCK_LEN) => Ok(()),
+        Ok(size) => Err(format_err!(
+            "read insufficient data from device EDID: got {} bytes, wanted {}",
+            size,
+            BLOCK_LEN
+        )),
+    }
+}
+
+/// checksum returns the sum of all bytes in a block, which should be zero for a valid block.
+fn checksum(block: &[u8]) -> u8 {
+    block.iter().fold(0u8, |acc, b| acc.wrapping_add(*b))
+}
+
+fn verify_base(edid: &[u8]) -> Result<()> {
+    if edid.len() < BLOCK_LEN {
+        return Err(format_err!(
+            "EDID is too short: got {} bytes, wanted at least {}",
+            edid.len(),
+            BLOCK_LEN
+        ));
+    }
+    if edid[..8] != HEADER {
+        return Err(format_err!(
+            "EDID has an invalid header: {:02x?}",
+            &edid[..8]
+        ));
+    }
+    match checksum(&edid[..BLOCK_LEN]) {
+        0 => Ok(()),
+        sum => Err(format_err!(
+            "EDID base block has an invalid checksum: bytes sum to {:#04x}",
+            sum
+        )),
+    }
 }
 
 /// read_mfg_id expects edid bytes 8 & 9 and returns the alphabetical manufacturer.
@@ -74,6 +295,94 @@ fn read_mfg_id(edid: &[u8]) -> Result<String> {
     Ok(std::str::from_utf8(&res.iter().map(|c| c + 65 - 1).collect::<Vec<_>>())?.to_owned())
 }
 
+/// read_manufacture_date expects edid bytes 16 & 17. A week of 0xFF marks the year as
+/// a model year, while 0 means the week is unspecified.
+fn read_manufacture_date(week: u8, year: u8) -> ManufactureDate {
+    ManufactureDate {
+        week: match week {
+            0 | 0xff => None,
+            w => Some(w),
+        },
+        year: 1990 + year as u16,
+        model_year: week == 0xff,
+    }
+}
+
+fn read_extension(block: &[u8]) -> Extension {
+    // https://en.wikipedia.org/wiki/Extended_Display_Identification_Data#Extensions_assigned_by_VESA
+    match block[0] {
+        0x02 => Extension::Cta861 { revision: block[1] },
+        0x70 => Extension::DisplayId { version: block[1] },
+        0xf0 => Extension::BlockMap,
+        tag => Extension::Other { tag },
+    }
+}
+
+/// Reads the maximum luminance from a CTA-861 extension's HDR static metadata or a
+/// DisplayID 2 extension's display parameters.
+fn read_max_luminance(block: &[u8]) -> Option<f64> {
+    match block[0] {
+        0x02 => read_cta_max_luminance(block),
+        0x70 => read_displayid_max_luminance(block),
+        _ => None,
+    }
+}
+
+fn read_cta_max_luminance(block: &[u8]) -> Option<f64> {
+    // Data blocks run from byte 4 to the offset of the detailed timings.
+    let end = (block[2] as usize).min(BLOCK_LEN - 1);
+    let mut i = 4;
+    while i < end {
+        let (tag, len) = (block[i] >> 5, (block[i] & 0x1f) as usize);
+        let payload = block.get(i + 1..(i + 1 + len).min(end))?;
+        // Extended tag 6 is the HDR static metadata. Its luminance are coded values,
+        // where 0 means it isn't given. The maximum frame-average luminance is the
+        // closer to a full white screen.
+        if tag == 7 && payload.first() == Some(&6) {
+            let given = |i: usize| payload.get(i).copied().filter(|&cv| cv != 0);
+            let cv = given(4).or_else(|| given(3))?;
+            return Some(50.0 * 2f64.powf(cv as f64 / 32.0));
+        }
+        i += 1 + len;
+    }
+    None
+}
+
+fn read_displayid_max_luminance(block: &[u8]) -> Option<f64> {
+    // DisplayID 1 has no luminance.
+    if block[1] < 0x20 {
+        return None;
+    }
+    let end = (5 + block[2] as usize).min(BLOCK_LEN - 1);
+    let mut i = 5;
+    while i + 3 <= end {
+        let (tag, len) = (block[i], block[i + 2] as usize);
+        let payload = block.get(i + 3..(i + 3 + len).min(end))?;
+        // The display parameters block has the full coverage luminance as a
+        // half-precision float.
+        if tag == 0x21 {
+            let bits = u16::from_le_bytes([*payload.get(0x15)?, *payload.get(0x16)?]);
+            return Some(half_float(bits)).filter(|l| l.is_finite() && *l > 0.0);
+        }
+        i += 3 + len;
+    }
+    None
+}
+
+fn half_float(bits: u16) -> f64 {
+    let frac = (bits & 0x3ff) as f64;
+    let magnitude = match (bits >> 10) & 0x1f {
+        0 => frac * 2f64.powi(-24),
+        0x1f => f64::INFINITY,
+        exp => (1.0 + frac / 1024.0) * 2f64.powi(exp as i32 - 15),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
 impl std::fmt::Display for DeviceInfo {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -84,9 +393,16 @@ impl std::fmt::Display for DeviceInfo {
     }
 }
 
+impl std::fmt::Display for EdidVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
 enum DispDescr {
     Serial(String),
     Model(String),
+    Alphanumeric(String),
     Other,
 }
 
@@ -98,14 +414,58 @@ fn read_descriptor(descr: &[u8]) -> Result<DispDescr> {
         ));
     }
 
+    // A non-zero pixel clock means this is a detailed timing descriptor.
+    if descr[0..2] != [0, 0] {
+        return Ok(DispDescr::Other);
+    }
+
     // https://en.wikipedia.org/wiki/Extended_Display_Identification_Data#Display_Descriptors
-    Ok(match &descr[3] {
-        0xff => DispDescr::Serial(std::str::from_utf8(&descr[5..18])?.trim().to_owned()),
-        0xfc => DispDescr::Model(std::str::from_utf8(&descr[5..18])?.trim().to_owned()),
-        _ => DispDescr::Other,
-    })
+    let (kind, text): (fn(String) -> DispDescr, _) = match &descr[3] {
+        0xff => (DispDescr::Serial, "serial"),
+        0xfe => (DispDescr::Alphanumeric, "alphanumeric"),
+        0xfc => (DispDescr::Model, "model"),
+        _ => return Ok(DispDescr::Other),
+    };
+
+    Ok(kind(decode_text(&descr[5..18]).unwrap_or_else(|| {
+        warn!(
+            "EDID {} descriptor is not valid text, ignoring it: {:02x?}",
+            text,
+            &descr[5..18]
+        );
+        String::new()
+    })))
+}
+
+/// decode_text decodes descriptor text per the EDID spec: code page 437, terminated
+/// by a line feed and padded with spaces. Returns None if there are control characters
+/// in the text.
+fn decode_text(text: &[u8]) -> Option<String> {
+    let end = text.iter().position(|c| *c == 0x0a).unwrap_or(text.len());
+
+    text[..end]
+        .iter()
+        .map(|c| match c {
+            0x20..=0x7e => Some(*c as char),
+            0x80..=0xff => CP437_HIGH.chars().nth((c - 0x80) as usize),
+            _ => None,
+        })
+        .collect::<Option<String>>()
+        .map(|s| s.trim_end_matches(' ').to_owned())
 }
 
+/// The upper half of code page 437, where it differs from ASCII. 0xFF is a non-breaking space.
+const CP437_HIGH: &str = concat!(
+    "ÇüéâäàåçêëèïîìÄÅ",
+    "ÉæÆôöòûùÿÖÜ¢£¥₧ƒ",
+    "áíóúñÑªº¿⌐¬½¼¡«»",
+    "░▒▓│┤╡╢╖╕╣║╗╝╜╛┐",
+    "└┴┬├─┼╞╟╚╔╩╦╠═╬╧",
+    "╨╤╥╙╘╒╓╫╪┘┌█▄▌▐▀",
+    "αßΓπΣσµτΦΘΩδ∞φε∩",
+    "≡±≥≤⌠⌡÷≈°∙·√ⁿ²■\u{a0}",
+);
+
 #[test]
 fn test_parse_mfg_example() {
     env_logger::init();
@@ -113,3 +473,115 @@ fn test_parse_mfg_example() {
 
     assert_eq!("IBM", res.unwrap());
 }
+
+#[cfg(test)]
+fn test_edid() -> Vec<u8> {
+    fn text_descr(tag: u8, text: &str) -> Vec<u8> {
+        let mut d = vec![0, 0, 0, tag, 0];
+        d.extend(text.bytes());
+        d.push(0x0a);
+        d.resize(18, 0x20);
+        d
+    }
+
+    let mut edid = HEADER.to_vec();
+    edid.extend(&[0x10, 0xac]); // DEL
+    edid.extend(&0xa0c7u16.to_le_bytes());
+    edid.extend(&0x3030_4c42u32.to_le_bytes());
+    edid.extend(&[10, 29, 1, 4, 0xa5, 53, 30]);
+    edid.resize(54, 0);
+    edid.extend(vec![0x01; 18]); // a timing descriptor
+    edid.extend(text_descr(0xff, "ABCD1234"));
+    edid.extend(text_descr(0xfc, "DELL U2415"));
+    edid.extend(text_descr(0xfe, "LM240WU"));
+    edid.push(1);
+    edid.push(0u8.wrapping_sub(checksum(&edid)));
+
+    let mut ext = vec![0x02, 0x03];
+    ext.resize(127, 0);
+    ext.push(0u8.wrapping_sub(checksum(&ext)));
+    edid.extend(ext);
+
+    edid
+}
+
+#[test]
+fn test_parse_edid() {
+    let info = DeviceInfo::parse(&test_edid()).unwrap();
+
+    assert_eq!("DEL", info.manufacturer);
+    assert_eq!("DELL U2415", info.model);
+    assert_eq!("ABCD1234", info.serial);
+    assert_eq!(vec!["LM240WU".to_owned()], info.alphanumeric);
+    assert_eq!(0xa0c7, info.product_code);
+    assert_eq!(0x3030_4c42, info.serial_number);
+    assert_eq!(
+        ManufactureDate {
+            week: Some(10),
+            year: 2019,
+            model_year: false
+        },
+        info.manufacture_date
+    );
+    assert_eq!(EdidVersion { major: 1, minor: 4 }, info.version);
+    assert_eq!(
+        Some(PhysicalSize {
+            width_cm: 53,
+            height_cm: 30
+        }),
+        info.physical_size
+    );
+    assert_eq!(vec![Extension::Cta861 { revision: 3 }], info.extensions);
+}
+
+#[test]
+fn test_parse_edid_checksums() {
+    let mut edid = test_edid();
+    edid[200] ^= 0xff;
+    let info = DeviceInfo::parse(&edid).unwrap();
+    assert!(info.extensions.is_empty());
+
+    edid[20] ^= 0xff;
+    assert!(DeviceInfo::parse(&edid).is_err());
+}
+
+#[test]
+fn test_max_luminance() {
+    let mut cta = vec![0x02, 0x03, 11, 0x00];
+    cta.extend(&[0xe6, 0x06, 0x05, 0x01, 96, 64, 0]);
+    cta.resize(BLOCK_LEN, 0);
+    assert_eq!(Some(200.0), read_max_luminance(&cta));
+    // without a frame-average, the maximum
+    cta[9] = 0;
+    assert_eq!(Some(400.0), read_max_luminance(&cta));
+    cta[2] = 0;
+    assert_eq!(None, read_max_luminance(&cta));
+
+    let mut displayid = vec![0x70, 0x20, 32, 0x00, 0x00, 0x21, 0x00, 29];
+    let mut params = vec![0; 29];
+    params[0x15..0x17].copy_from_slice(&[0xb0, 0x5c]);
+    displayid.extend(params);
+    displayid.resize(BLOCK_LEN, 0);
+    assert_eq!(Some(300.0), read_max_luminance(&displayid));
+    displayid[1] = 0x13;
+    assert_eq!(None, read_max_luminance(&displayid));
+}
+
+#[test]
+fn test_decode_text() {
+    assert_eq!(128, CP437_HIGH.chars().count());
+    assert_eq!(
+        Some("DELL U2415".to_owned()),
+        decode_text(b"DELL U2415\n  ")
+    );
+    assert_eq!(Some("Ü 1".to_owned()), decode_text(b"\x9a 1   "));
+    assert_eq!(None, decode_text(b"AB\x00\x01\n"));
+
+    let mut edid = test_edid();
+    edid[95..97].copy_from_slice(&[0x00, 0x07]);
+    let sum = checksum(&edid[..127]);
+    edid[127] = 0u8.wrapping_sub(sum);
+    let info = DeviceInfo::parse(&edid).unwrap();
+    assert_eq!("", info.model);
+    assert_eq!("ABCD1234", info.serial);
+}
@@ -1,9 +1,23 @@
+mod bus;
+mod capabilities;
+mod curve;
 mod device;
 mod device_matcher;
 mod displays;
+mod drm;
 mod edid;
+mod scheduler;
 
-pub use device::{BrightnessHardware, Device, I2CDevice};
+pub use bus::Bus;
+pub(crate) use bus::NON_DDC_ADAPTERS;
+pub use capabilities::{Capabilities, MccsVersion};
+pub use curve::{BrightnessCurve, Luminance, Response, Scale};
+pub(crate) use device::adapter_name;
+pub use device::{
+    BrightnessHardware, Device, I2CDevice, WriteOutcome, BRIGHTNESS, BRIGHTNESS_TOLERANCE,
+};
 pub use device_matcher::DeviceMatcher;
-pub use displays::{BrightnessOps, Display, Displays};
-pub use edid::DeviceInfo;
+pub use displays::{shared_identities, Display, Displays};
+pub use drm::Connector;
+pub use edid::{DeviceInfo, EdidVersion, Extension, ManufactureDate, PhysicalSize};
+pub use scheduler::Scheduler;
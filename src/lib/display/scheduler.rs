@@ -0,0 +1,122 @@
+use std::cmp::max;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use ddc::{
+    commands::{CapabilitiesRequest, GetVcpFeature, SetVcpFeature},
+    Command, CommandResult, DdcCommandRaw, FeatureCode, VcpValue, DELAY_COMMAND_FAILED_MS,
+};
+
+use super::I2CDevice;
+use crate::{config::DdcTiming, types::*};
+
+/// Scheduler owns a bus and serializes the DDC/CI commands sent over it. Between
+/// commands it waits at least the MCCS delay for the previous command or the configured
+/// delay, whichever is longer. Its methods block, so callers on the async runtime should
+/// run them with spawn_blocking.
+pub struct Scheduler {
+    inner: I2CDevice,
+    timing: DdcTiming,
+    /// when the bus is next ready for a command.
+    ready_at: Option<Instant>,
+}
+
+impl Scheduler {
+    pub fn new(inner: I2CDevice) -> Self {
+        Self {
+            inner,
+            timing: DdcTiming::default(),
+            ready_at: None,
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: DdcTiming) {
+        self.timing = timing;
+    }
+
+    pub fn timing(&self) -> DdcTiming {
+        self.timing
+    }
+
+    pub fn get_vcp_feature(&mut self, code: FeatureCode) -> Result<VcpValue> {
+        self.execute(GetVcpFeature::new(code))
+    }
+
+    pub fn set_vcp_feature(&mut self, code: FeatureCode, value: u16) -> Result<()> {
+        self.execute(SetVcpFeature::new(code, value))
+    }
+
+    /// Reads the raw capabilities string, which is returned in fragments.
+    pub fn capabilities_string(&mut self) -> Result<Vec<u8>> {
+        let mut caps = vec![];
+        loop {
+            let reply = self.execute(CapabilitiesRequest::new(caps.len() as u16))?;
+            if reply.offset as usize != caps.len() {
+                return Err(format_err!(
+                    "capabilities fragment at offset {} when {} was requested",
+                    reply.offset,
+                    caps.len()
+                ));
+            } else if reply.data.is_empty() {
+                break;
+            }
+
+            caps.extend(reply.data.iter());
+        }
+
+        Ok(caps)
+    }
+
+    /// Waits until the bus is ready, then returns the device for commands which aren't
+    /// DDC/CI, like EDID reads.
+    pub fn device(&mut self) -> &mut I2CDevice {
+        self.wait();
+        &mut self.inner
+    }
+
+    fn wait(&mut self) {
+        if let Some(at) = self.ready_at.take() {
+            let now = Instant::now();
+            if at > now {
+                sleep(at - now);
+            }
+        }
+    }
+
+    fn execute<C: Command>(&mut self, cmd: C) -> Result<C::Ok> {
+        let mut data = [0u8; 36];
+        let len = cmd
+            .encode(&mut data)
+            .map_err(|e| format_err!("failed to encode command: {}", e))?;
+        let mut out = [0u8; 36 + 3];
+        let out = if C::Ok::MAX_LEN > 0 {
+            &mut out[..C::Ok::MAX_LEN + 3]
+        } else {
+            &mut []
+        };
+
+        self.wait();
+        let res = self
+            .inner
+            .execute_raw(
+                &data[..len],
+                out,
+                max(
+                    Duration::from_millis(C::DELAY_RESPONSE_MS),
+                    Duration::from_millis(self.timing.reply_delay_ms),
+                ),
+            )
+            .map_err(Error::from)
+            .and_then(|reply| C::Ok::decode(reply).map_err(Error::from));
+
+        let spec_delay = match res {
+            Ok(_) => C::DELAY_COMMAND_MS,
+            Err(_) => DELAY_COMMAND_FAILED_MS,
+        };
+        self.ready_at = Some(
+            Instant::now() + Duration::from_millis(max(spec_delay, self.timing.command_delay_ms)),
+        );
+
+        res
+    }
+}
@@ -0,0 +1,250 @@
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Serialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::time::timeout;
+
+use crate::{config::HookOpts, logging::*, types::*};
+
+/// Names of the events hooks may run on.
+pub const EVENTS: &[&str] = &[
+    "sunrise",
+    "sunset",
+    "override_start",
+    "override_end",
+    "display_added",
+    "display_removed",
+    "ddc_failure",
+];
+
+/// Something the daemon did or noticed which hooks may run on. Displays are named by
+/// their identity and i2c bus. Brightness only changes at sunrise and sunset, so those
+/// are the only phase transitions.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// displays are being set to their daytime brightness.
+    Sunrise,
+    /// displays are being set to their nighttime brightness.
+    Sunset,
+    /// brightness changed with a display's buttons is kept until the phase ends.
+    OverrideStart {
+        display: String,
+        bus: String,
+        brightness: f64,
+    },
+    /// a display's kept brightness was dropped, as its phase ended or the profile
+    /// was switched.
+    OverrideEnd { display: String, bus: String },
+    /// the daemon started managing a display.
+    DisplayAdded { display: String, bus: String },
+    /// the daemon stopped managing a display, as a profile switch left it unclaimed.
+    DisplayRemoved { display: String, bus: String },
+    /// setting a display's brightness failed. It's retried until it succeeds.
+    DdcFailure {
+        display: String,
+        bus: String,
+        error: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Sunrise => "sunrise",
+            Self::Sunset => "sunset",
+            Self::OverrideStart { .. } => "override_start",
+            Self::OverrideEnd { .. } => "override_end",
+            Self::DisplayAdded { .. } => "display_added",
+            Self::DisplayRemoved { .. } => "display_removed",
+            Self::DdcFailure { .. } => "ddc_failure",
+        }
+    }
+}
+
+/// An event along with when it happened, as given to hooks.
+#[derive(Serialize)]
+struct Notice<'a> {
+    #[serde(flatten)]
+    event: &'a Event,
+    /// RFC 3339 local time.
+    time: String,
+}
+
+impl<'a> Notice<'a> {
+    /// The notice as environment variables, one per field, like DLUX_EVENT=sunset.
+    fn env(&self) -> Result<Vec<(String, String)>> {
+        let fields = match serde_json::to_value(self)? {
+            serde_json::Value::Object(fields) => fields,
+            _ => return Err(format_err!("events are expected to serialize to objects")),
+        };
+        Ok(fields
+            .into_iter()
+            .map(|(key, value)| {
+                let value = match value {
+                    serde_json::Value::String(s) => s,
+                    v => v.to_string(),
+                };
+                (format!("DLUX_{}", key.to_uppercase()), value)
+            })
+            .collect())
+    }
+}
+
+/// Hooks runs the configured commands when events happen. Commands run in the
+/// background so they never hold up the daemon, at most max_concurrent at a time.
+pub struct Hooks {
+    hooks: Vec<Arc<HookOpts>>,
+    limit: Arc<Semaphore>,
+}
+
+impl Hooks {
+    pub fn new(hooks: Vec<HookOpts>, max_concurrent: usize) -> Self {
+        Self {
+            hooks: hooks.into_iter().map(Arc::new).collect(),
+            limit: Arc::new(Semaphore::new(max_concurrent.max(1))),
+        }
+    }
+
+    /// Starts the hooks for the event without waiting for them.
+    pub fn fire(&self, event: Event) {
+        let hooks = self
+            .hooks
+            .iter()
+            .filter(|h| h.events.is_empty() || h.events.iter().any(|e| e == event.name()))
+            .collect::<Vec<_>>();
+        if hooks.is_empty() {
+            return;
+        }
+
+        let notice = Notice {
+            event: &event,
+            time: chrono::Local::now().to_rfc3339(),
+        };
+        let described = serde_json::to_string(&notice)
+            .map_err(Error::from)
+            .and_then(|json| Ok((json, notice.env()?)));
+        let (input, env) = match described {
+            Ok(described) => described,
+            Err(e) => {
+                warn!("failed to describe {} for hooks: {:#}", event.name(), e);
+                return;
+            }
+        };
+
+        for hook in hooks {
+            let (hook, limit) = (hook.clone(), self.limit.clone());
+            let (input, env) = (input.clone(), env.clone());
+            let name = event.name();
+            tokio::spawn(async move {
+                let _permit = match limit.acquire_owned().await {
+                    Ok(permit) => permit,
+                    Err(_) => return,
+                };
+                debug!("running hook {:?} for {}", hook.command, name);
+                if let Err(e) = run(&hook, &input, env).await {
+                    warn!("hook {:?} for {} failed: {:#}", hook.command, name, e);
+                }
+            });
+        }
+    }
+}
+
+/// Runs the hook's command with sh, giving it the event as JSON on stdin. It's killed
+/// if it runs past its timeout.
+async fn run(hook: &HookOpts, input: &str, env: Vec<(String, String)>) -> Result<()> {
+    let mut child = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(&hook.command)
+        .envs(env)
+        .stdin(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .context("failed to start it")?;
+    let mut stdin = child.stdin.take();
+
+    let limit = Duration::from_secs(hook.timeout_secs);
+    let status = timeout(limit, async {
+        if let Some(stdin) = stdin.as_mut() {
+            // Commands which don't read the event may exit before it's written.
+            if let Err(e) = stdin.write_all(input.as_bytes()).await {
+                trace!("failed to write event to hook: {}", e);
+            }
+        }
+        drop(stdin.take());
+        child.wait().await
+    })
+    .await;
+
+    match status {
+        Ok(status) if status.as_ref().is_ok_and(|s| s.success()) => Ok(()),
+        Ok(status) => Err(format_err!("it exited with {}", status?)),
+        Err(_) => {
+            child.kill().await?;
+            Err(format_err!("it was killed after running for {:?}", limit))
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_hooks() {
+    let dir = std::env::temp_dir().join(format!("dlux-test-hooks-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out");
+    let hook = |events: &[&str], command: String, timeout_secs| HookOpts {
+        events: events.iter().map(|e| e.to_string()).collect(),
+        command,
+        timeout_secs,
+    };
+
+    let hooks = Hooks::new(
+        vec![
+            hook(
+                &["display_added"],
+                format!(
+                    "echo \"$DLUX_EVENT $DLUX_DISPLAY $DLUX_BUS\" > {0}.env; cat > {0}.json",
+                    out.display()
+                ),
+                10,
+            ),
+            hook(&["sunset"], "sleep 30".to_owned(), 1),
+            hook(&["sunrise"], format!("touch {}.sunrise", out.display()), 10),
+        ],
+        1,
+    );
+
+    // firing doesn't wait for the slow hook, which holds the only slot until it's
+    // killed.
+    let start = std::time::Instant::now();
+    hooks.fire(Event::Sunset);
+    hooks.fire(Event::DisplayAdded {
+        display: "DEL DELL U2415".to_owned(),
+        bus: "/dev/i2c-3".to_owned(),
+    });
+    assert!(start.elapsed() < Duration::from_millis(500));
+
+    let json = out.with_extension("json");
+    for _ in 0..100 {
+        if std::fs::read_to_string(&json).is_ok_and(|s| s.ends_with('}')) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    assert!(start.elapsed() >= Duration::from_secs(1));
+    assert_eq!(
+        "display_added DEL DELL U2415 /dev/i2c-3\n",
+        std::fs::read_to_string(out.with_extension("env")).unwrap()
+    );
+    let event: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    assert_eq!("display_added", event["event"]);
+    assert_eq!("/dev/i2c-3", event["bus"]);
+    assert!(event["time"].is_string());
+    assert!(!out.with_extension("sunrise").exists());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
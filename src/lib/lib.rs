@@ -1,12 +1,16 @@
 pub mod alarm;
 pub mod config;
+pub mod control;
 pub mod display;
+pub mod hooks;
+pub mod location;
 pub mod logging;
+pub mod state;
 pub mod types;
 
 pub mod prelude {
     pub use super::display::{
-        BrightnessHardware, BrightnessOps, Device, DeviceInfo, DeviceMatcher, Display, Displays,
-        I2CDevice,
+        BrightnessHardware, Capabilities, Connector, Device, DeviceInfo, DeviceMatcher, Display,
+        Displays, I2CDevice, WriteOutcome,
     };
 }
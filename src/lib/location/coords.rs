@@ -0,0 +1,177 @@
+use super::{check_latitude, check_longitude, geohash, plus_code};
+use crate::types::*;
+
+/// Which coordinate a hemisphere letter marks an angle as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Axis {
+    Latitude,
+    Longitude,
+}
+
+/// Parses a latitude in decimal degrees or degrees, minutes, and seconds, with an
+/// optional sign or N/S hemisphere: "40.7128", "40°42'46\"N", or "S33 52.1".
+pub fn parse_latitude(s: &str) -> Result<f64> {
+    match parse_angle(s)? {
+        (_, Some(Axis::Longitude)) => Err(format_err!(
+            "{:?} is a longitude, but a latitude was expected",
+            s
+        )),
+        (lat, _) => check_latitude(lat),
+    }
+}
+
+/// Parses a longitude like parse_latitude, with an E/W hemisphere.
+pub fn parse_longitude(s: &str) -> Result<f64> {
+    match parse_angle(s)? {
+        (_, Some(Axis::Latitude)) => Err(format_err!(
+            "{:?} is a latitude, but a longitude was expected",
+            s
+        )),
+        (lon, _) => check_longitude(lon),
+    }
+}
+
+/// Parses a latitude and longitude written together, as map apps do: a pair like
+/// "40.7128,-74.0060" or "40°42'46\"N 74°0'21\"W", a geohash, or a full plus code.
+pub fn parse_location(s: &str) -> Result<(f64, f64)> {
+    let s = s.trim();
+    if let Some((lat, lon)) = s.split_once(',') {
+        return parse_pair(lat, lon);
+    }
+    if s.contains('+') {
+        return plus_code::decode(s);
+    }
+
+    // without a comma, the pair is split at whichever space leaves a valid
+    // coordinate on each side.
+    let pair = s
+        .match_indices(char::is_whitespace)
+        .find_map(|(i, _)| parse_pair(&s[..i], &s[i..]).ok());
+    match pair {
+        Some(pair) => Ok(pair),
+        None if s.contains(char::is_whitespace) => Err(format_err!(
+            "{:?} isn't a latitude and longitude separated by a comma or space",
+            s
+        )),
+        None => geohash::decode(s),
+    }
+}
+
+/// Parses the halves of a coordinate pair. They're swapped if their hemispheres show
+/// the longitude came first.
+fn parse_pair(first: &str, second: &str) -> Result<(f64, f64)> {
+    let first = parse_angle(first)?;
+    let second = parse_angle(second)?;
+    let (lat, lon) = match (first.1, second.1) {
+        (Some(Axis::Longitude), _) | (_, Some(Axis::Latitude)) => (second, first),
+        _ => (first, second),
+    };
+    match (lat.1, lon.1) {
+        (Some(Axis::Longitude), _) | (_, Some(Axis::Latitude)) => Err(format_err!(
+            "a latitude and a longitude are needed, but both have the same hemisphere"
+        )),
+        _ => Ok((check_latitude(lat.0)?, check_longitude(lon.0)?)),
+    }
+}
+
+/// Parses an angle in degrees, signed or with a hemisphere letter before or after it.
+/// Degrees may be followed by minutes and seconds, separated by their symbols or by
+/// spaces, and only the last part may have a fraction.
+fn parse_angle(s: &str) -> Result<(f64, Option<Axis>)> {
+    let input = s;
+    let s = s.trim();
+    let hemisphere = |c: char| match c.to_ascii_uppercase() {
+        'N' => Some((1.0, Axis::Latitude)),
+        'S' => Some((-1.0, Axis::Latitude)),
+        'E' => Some((1.0, Axis::Longitude)),
+        'W' => Some((-1.0, Axis::Longitude)),
+        _ => None,
+    };
+
+    let (s, hemi) = match (s.chars().next(), s.chars().last()) {
+        (Some(c), _) if hemisphere(c).is_some() => (&s[c.len_utf8()..], hemisphere(c)),
+        (_, Some(c)) if hemisphere(c).is_some() => (&s[..s.len() - c.len_utf8()], hemisphere(c)),
+        _ => (s, None),
+    };
+    let s = s.trim();
+    let (s, sign) = match s.strip_prefix('-') {
+        Some(rest) => (rest, -1.0),
+        None => (s.strip_prefix('+').unwrap_or(s), 1.0),
+    };
+    if sign < 0.0 && hemi.is_some() {
+        return Err(format_err!(
+            "{:?} has both a sign and a hemisphere",
+            input.trim()
+        ));
+    }
+
+    let parts = s
+        .split(|c: char| c.is_whitespace() || "°º˚'′’\"″”".contains(c))
+        .filter(|p| !p.is_empty())
+        .collect::<Vec<_>>();
+    let invalid = || format_err!("{:?} isn't a coordinate", input.trim());
+    if parts.is_empty() || parts.len() > 3 {
+        return Err(invalid());
+    }
+    if parts[..parts.len() - 1].iter().any(|p| p.contains('.')) {
+        return Err(format_err!(
+            "{:?} has a fraction before its last part",
+            input.trim()
+        ));
+    }
+
+    let mut degrees = 0.0;
+    for (i, part) in parts.iter().enumerate() {
+        if !part.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return Err(invalid());
+        }
+        let value = part.parse::<f64>().map_err(|_| invalid())?;
+        if i > 0 && value >= 60.0 {
+            return Err(format_err!(
+                "{:?} has minutes or seconds of 60 or more",
+                input.trim()
+            ));
+        }
+        degrees += value / 60f64.powi(i as i32);
+    }
+
+    let (hemi_sign, axis) = hemi.map(|(s, a)| (s, Some(a))).unwrap_or((1.0, None));
+    Ok((sign * hemi_sign * degrees, axis))
+}
+
+#[test]
+fn test_parse_coordinates() {
+    let close = |expected: f64, actual: f64| (expected - actual).abs() < 1e-4;
+
+    assert_eq!(40.7128, parse_latitude("40.7128").unwrap());
+    assert_eq!(-74.006, parse_longitude("-74.006").unwrap());
+    assert_eq!(-74.0, parse_longitude("74.0W").unwrap());
+    assert_eq!(-33.5, parse_latitude("S 33.5").unwrap());
+    assert!(close(40.71278, parse_latitude("40°42'46\"N").unwrap()));
+    assert!(close(40.71278, parse_latitude("40° 42′ 46″ n").unwrap()));
+    assert!(close(-74.0058, parse_longitude("74 0 21 W").unwrap()));
+    assert!(close(51.5, parse_latitude("51°30'").unwrap()));
+
+    assert!(parse_latitude("74.0W").is_err());
+    assert!(parse_longitude("40N").is_err());
+    assert!(parse_latitude("-40S").is_err());
+    assert!(parse_latitude("40°61'").is_err());
+    assert!(parse_latitude("40.5°30'").is_err());
+    assert!(parse_latitude("91").is_err());
+    assert!(parse_latitude("forty").is_err());
+
+    let (lat, lon) = parse_location("40.7128,-74.0060").unwrap();
+    assert_eq!((40.7128, -74.006), (lat, lon));
+    let (lat, lon) = parse_location("40°42'46\"N 74°0'21\"W").unwrap();
+    assert!(
+        close(40.71278, lat) && close(-74.00583, lon),
+        "{} {}",
+        lat,
+        lon
+    );
+    // the longitude first
+    let (lat, lon) = parse_location("74.006W, 40.7128N").unwrap();
+    assert_eq!((40.7128, -74.006), (lat, lon));
+    assert!(parse_location("40N, 74N").is_err());
+    assert!(parse_location("40.7128 -74.0060 12").is_err());
+}
@@ -0,0 +1,176 @@
+use crate::types::*;
+
+const CITIES: &str = include_str!("cities.tsv");
+const COUNTRIES: &str = include_str!("countries.tsv");
+
+/// A city known to the embedded gazetteer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct City {
+    pub name: &'static str,
+    /// state or province, if the city is given with one.
+    pub region: Option<&'static str>,
+    /// ISO 3166 country code.
+    pub country: &'static str,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl City {
+    pub fn country_name(&self) -> &'static str {
+        rows(COUNTRIES)
+            .find(|cols| cols[0] == self.country)
+            .and_then(|cols| cols.get(1).copied())
+            .unwrap_or(self.country)
+    }
+}
+
+impl std::fmt::Display for City {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        if let Some(region) = self.region {
+            write!(f, ", {}", region)?;
+        }
+        write!(f, ", {} ({})", self.country_name(), self.country)
+    }
+}
+
+fn rows(table: &'static str) -> impl Iterator<Item = Vec<&'static str>> {
+    table
+        .lines()
+        .filter(|l| !l.starts_with('#') && !l.trim().is_empty())
+        .map(|l| l.split('\t').collect())
+}
+
+fn cities() -> impl Iterator<Item = City> {
+    rows(CITIES).filter_map(|cols| match cols[..] {
+        [name, region, country, lat, lon] => Some(City {
+            name,
+            region: Some(region).filter(|r| !r.is_empty()),
+            country,
+            latitude: lat.parse().ok()?,
+            longitude: lon.parse().ok()?,
+        }),
+        _ => None,
+    })
+}
+
+/// Lowercases s and strips common accents, so "São Paulo" finds "Sao Paulo".
+fn fold(s: &str) -> String {
+    s.trim()
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' => 'a',
+            'ç' => 'c',
+            'è' | 'é' | 'ê' | 'ë' => 'e',
+            'ì' | 'í' | 'î' | 'ï' => 'i',
+            'ñ' => 'n',
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' => 'o',
+            'ù' | 'ú' | 'û' | 'ü' => 'u',
+            'ý' | 'ÿ' => 'y',
+            '-' | '.' => ' ',
+            c => c,
+        })
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Cities matching a query like "Seattle" or "Portland, OR, US". Parts after the name
+/// narrow the matches by region, country code, or country name. Cities whose name is
+/// the query come first, followed by those whose name starts with it.
+pub fn search(query: &str) -> Vec<City> {
+    let mut parts = query.split(',').map(fold);
+    let name = parts.next().unwrap_or_default();
+    let filters = parts.filter(|p| !p.is_empty()).collect::<Vec<_>>();
+    if name.is_empty() {
+        return vec![];
+    }
+
+    let (mut exact, prefixed): (Vec<_>, Vec<_>) = cities()
+        .filter(|c| fold(c.name).starts_with(&name))
+        .filter(|c| {
+            filters.iter().all(|f| {
+                c.region.map(fold).as_ref() == Some(f)
+                    || &fold(c.country) == f
+                    || &fold(c.country_name()) == f
+            })
+        })
+        .partition(|c| fold(c.name) == name);
+    exact.extend(prefixed);
+    exact
+}
+
+/// The coordinates of the city the query names. It's an error if no city or more than
+/// one city has the name, after narrowing by region and country.
+pub fn locate_city(query: &str) -> Result<(f64, f64)> {
+    let matches = search(query);
+    let exact = matches
+        .iter()
+        .filter(|c| fold(c.name) == fold(query.split(',').next().unwrap_or_default()))
+        .collect::<Vec<_>>();
+
+    match exact[..] {
+        [city] => Ok((city.latitude, city.longitude)),
+        [] if matches.is_empty() => Err(format_err!(
+            "no city matches {:?}; try `dlux locate` with part of its name, or give coordinates",
+            query
+        )),
+        [] => Err(format_err!(
+            "no city is named {:?}, did you mean {}?",
+            query,
+            list(matches.iter())
+        )),
+        _ => Err(format_err!(
+            "{:?} is ambiguous, add a region or country to choose between {}",
+            query,
+            list(exact.into_iter())
+        )),
+    }
+}
+
+fn list<'a, I: Iterator<Item = &'a City>>(cities: I) -> String {
+    let cities = cities.map(|c| format!("\"{}\"", c)).collect::<Vec<_>>();
+    match cities.len() {
+        n if n > 5 => format!("{}, and {} others", cities[..5].join(", "), n - 5),
+        _ => cities.join(", "),
+    }
+}
+
+#[test]
+fn test_locate_city() {
+    let (lat, lon) = locate_city("Seattle, US").unwrap();
+    assert_eq!((47.6062, -122.3321), (lat, lon));
+    let (lat, _) = locate_city("são paulo").unwrap();
+    assert!((lat + 23.5).abs() < 0.1, "{}", lat);
+    assert!(locate_city("new york, united states").is_ok());
+    assert!(locate_city("Portland, ME").is_ok());
+    assert!(locate_city("Valencia, ES").is_ok());
+
+    let err = locate_city("Portland").unwrap_err().to_string();
+    assert!(err.contains("ambiguous"), "{}", err);
+    let err = locate_city("Seatt").unwrap_err().to_string();
+    assert!(
+        err.contains("did you mean \"Seattle, WA, United States (US)\""),
+        "{}",
+        err
+    );
+    assert!(locate_city("Seattle, GB").is_err());
+    assert!(locate_city("Atlantis").is_err());
+
+    // every city can be used as a location
+    for city in cities() {
+        let loc = super::Location {
+            latitude: city.latitude,
+            longitude: city.longitude,
+            altitude: 0.0,
+        };
+        assert!(loc.check_sun().is_ok(), "{}", city);
+    }
+    assert!(locate_city("Longyearbyen").is_err());
+
+    let found = search("san");
+    assert!(found.iter().any(|c| c.name == "San Diego"));
+    assert!(found.iter().all(|c| c.name.starts_with("San")));
+}
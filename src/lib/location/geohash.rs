@@ -0,0 +1,48 @@
+use crate::types::*;
+
+const ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+/// Decodes a geohash to the center of the cell it names.
+pub fn decode(hash: &str) -> Result<(f64, f64)> {
+    let mut lat = (-90.0, 90.0);
+    let mut lon = (-180.0, 180.0);
+    // bits alternate between longitude and latitude, starting with longitude
+    let mut even = true;
+
+    if hash.is_empty() {
+        return Err(format_err!("a location is empty"));
+    }
+    for c in hash.chars() {
+        let bits = ALPHABET
+            .iter()
+            .position(|&a| a as char == c.to_ascii_lowercase())
+            .ok_or_else(|| {
+                format_err!("{:?} isn't a coordinate pair, geohash, or plus code", hash)
+            })?;
+        for shift in (0..5).rev() {
+            let range = if even { &mut lon } else { &mut lat };
+            let mid = (range.0 + range.1) / 2.0;
+            if bits >> shift & 1 == 1 {
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+            even = !even;
+        }
+    }
+
+    Ok(((lat.0 + lat.1) / 2.0, (lon.0 + lon.1) / 2.0))
+}
+
+#[test]
+fn test_decode() {
+    let (lat, lon) = decode("u4pruydqqvj").unwrap();
+    assert!((lat - 57.64911).abs() < 1e-5, "{}", lat);
+    assert!((lon - 10.40744).abs() < 1e-5, "{}", lon);
+
+    let (lat, lon) = decode("DR5REGY").unwrap();
+    assert!((lat - 40.7128).abs() < 1e-2, "{}", lat);
+    assert!((lon + 74.006).abs() < 1e-2, "{}", lon);
+
+    assert!(decode("seattle").is_err());
+}
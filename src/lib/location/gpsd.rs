@@ -0,0 +1,321 @@
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{sleep, timeout, Instant};
+
+use super::{check_latitude, check_longitude, Location};
+use crate::{config::GpsdOpts, logging::*, types::*};
+
+/// How long to wait before reconnecting to gpsd, and at most between reports before
+/// checking whether the last fix is too old.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Asks gpsd to stream reports as JSON.
+const WATCH: &[u8] = b"?WATCH={\"enable\":true,\"json\":true};\n";
+
+/// A position reported by gpsd.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fix {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: Option<f64>,
+}
+
+/// What a line from gpsd says about the position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Report {
+    Fix(Fix),
+    /// the receiver is reporting but doesn't know where it is.
+    NoFix,
+    /// a report which isn't about the position.
+    Other,
+}
+
+/// The fields of gpsd's time-position-velocity reports which are used.
+#[derive(Deserialize)]
+struct Tpv {
+    class: String,
+    #[serde(default)]
+    mode: u8,
+    lat: Option<f64>,
+    lon: Option<f64>,
+    #[serde(rename = "altMSL")]
+    alt_msl: Option<f64>,
+    alt: Option<f64>,
+}
+
+pub fn parse_report(line: &str) -> Result<Report> {
+    let tpv: Tpv = serde_json::from_str(line)
+        .with_context(|| format!("unexpected report from gpsd: {:?}", line))?;
+    if tpv.class != "TPV" {
+        return Ok(Report::Other);
+    }
+
+    // modes 2 and 3 are 2D and 3D fixes.
+    Ok(match (tpv.mode, tpv.lat, tpv.lon) {
+        (2..=3, Some(latitude), Some(longitude)) => Report::Fix(Fix {
+            latitude: check_latitude(latitude).context("gpsd reported an invalid position")?,
+            longitude: check_longitude(longitude).context("gpsd reported an invalid position")?,
+            altitude: tpv
+                .alt_msl
+                .or(tpv.alt)
+                .filter(|a| tpv.mode == 3 && a.is_finite()),
+        }),
+        _ => Report::NoFix,
+    })
+}
+
+/// A connection to gpsd which is watching for reports.
+pub struct Gpsd {
+    lines: Lines<BufReader<TcpStream>>,
+}
+
+impl Gpsd {
+    pub async fn connect(address: &str) -> Result<Self> {
+        let mut stream = TcpStream::connect(address)
+            .await
+            .with_context(|| format!("failed to connect to gpsd at {}", address))?;
+        stream.write_all(WATCH).await?;
+        Ok(Self {
+            lines: BufReader::new(stream).lines(),
+        })
+    }
+
+    /// Waits for the next report. It's cancel safe, so it may be raced against a
+    /// timeout: a partly read report stays buffered until the next call. Reports which
+    /// can't be used are warned about and skipped, rather than dropping the connection.
+    pub async fn next(&mut self) -> Result<Report> {
+        match self.lines.next_line().await? {
+            Some(line) => Ok(parse_report(&line).unwrap_or_else(|e| {
+                warn!("{:#}", e);
+                Report::Other
+            })),
+            None => Err(format_err!("gpsd closed the connection")),
+        }
+    }
+}
+
+/// GpsdSource follows the position gpsd reports, falling back to the configured
+/// location until there's a fix and after the last fix is too old.
+pub struct GpsdSource {
+    opts: GpsdOpts,
+    configured: Location,
+    current: Location,
+    last_fix: Option<Instant>,
+}
+
+impl GpsdSource {
+    pub fn new(opts: GpsdOpts, configured: Location) -> Self {
+        Self {
+            opts,
+            configured,
+            current: configured,
+            last_fix: None,
+        }
+    }
+
+    /// Follows gpsd in a task, sending the location whenever it changes.
+    pub fn spawn(self) -> watch::Receiver<Location> {
+        let (tx, rx) = watch::channel(self.current);
+        tokio::spawn(self.run(tx));
+        rx
+    }
+
+    async fn run(mut self, tx: watch::Sender<Location>) {
+        let mut conn: Option<Gpsd> = None;
+        while !tx.is_closed() {
+            let gpsd = match &mut conn {
+                Some(gpsd) => gpsd,
+                None => match Gpsd::connect(&self.opts.address).await {
+                    Ok(gpsd) => {
+                        info!("following the position reported by gpsd");
+                        conn.insert(gpsd)
+                    }
+                    Err(e) => {
+                        debug!("{:#}, retrying in {:?}", e, RECONNECT_INTERVAL);
+                        sleep(RECONNECT_INTERVAL).await;
+                        self.expire(&tx);
+                        continue;
+                    }
+                },
+            };
+
+            match timeout(RECONNECT_INTERVAL, gpsd.next()).await {
+                Ok(Ok(Report::Fix(fix))) => self.fixed(fix, &tx),
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => {
+                    warn!("lost gpsd: {:#}", e);
+                    conn = None;
+                }
+                Err(_) => trace!("no reports from gpsd for {:?}", RECONNECT_INTERVAL),
+            }
+            self.expire(&tx);
+        }
+    }
+
+    fn fixed(&mut self, fix: Fix, tx: &watch::Sender<Location>) {
+        let location = Location {
+            latitude: fix.latitude,
+            longitude: fix.longitude,
+            altitude: fix.altitude.unwrap_or(self.configured.altitude),
+        };
+        // Brightness can't be scheduled there, so the last good location is kept
+        // until it's too old.
+        if let Err(e) = location.check_sun() {
+            warn!("ignoring the position reported by gpsd: {}", e);
+            return;
+        }
+        self.last_fix = Some(Instant::now());
+
+        let moved = self.current.distance_km(&location);
+        if moved >= self.opts.min_distance_km {
+            info!("moved {:.0} km to {} according to gpsd", moved, location);
+            self.current = location;
+            let _ = tx.send(location);
+        } else {
+            trace!("gpsd reports {}, {:.1} km away", location, moved);
+        }
+    }
+
+    /// Falls back to the configured location when the last fix is too old.
+    fn expire(&mut self, tx: &watch::Sender<Location>) {
+        let max_age = Duration::from_secs(self.opts.max_fix_age_mins * 60);
+        let stale = self.last_fix.is_some_and(|at| at.elapsed() > max_age);
+        if stale && self.current != self.configured {
+            info!(
+                "gpsd hasn't had a fix for {} minutes, using the configured location {}",
+                self.opts.max_fix_age_mins, self.configured
+            );
+            self.current = self.configured;
+            self.last_fix = None;
+            let _ = tx.send(self.configured);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_gpsd_source() {
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    // a stand-in for gpsd which sends the reports it's given.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (reports, mut pending) = tokio::sync::mpsc::unbounded_channel::<&str>();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut watch = vec![0; WATCH.len()];
+        stream.read_exact(&mut watch).await.unwrap();
+        assert_eq!(WATCH, &watch[..]);
+        while let Some(report) = pending.recv().await {
+            stream.write_all(report.as_bytes()).await.unwrap();
+        }
+    });
+
+    let configured = Location {
+        latitude: 47.6062,
+        longitude: -122.3321,
+        altitude: 50.0,
+    };
+    let opts = GpsdOpts {
+        address,
+        min_distance_km: 10.0,
+        max_fix_age_mins: 60,
+    };
+    let mut rx = GpsdSource::new(opts, configured).spawn();
+    assert_eq!(configured, *rx.borrow());
+
+    let changed = |rx: &mut watch::Receiver<Location>| {
+        let mut rx = rx.clone();
+        async move {
+            timeout(Duration::from_secs(5), rx.changed())
+                .await
+                .expect("location didn't change")
+                .unwrap();
+            let location = *rx.borrow();
+            location
+        }
+    };
+
+    reports
+        .send("{\"class\":\"VERSION\",\"release\":\"3.25\"}\n{\"class\":\"TPV\",\"mode\":1}\n")
+        .unwrap();
+    // a few km away isn't far enough to matter
+    reports
+        .send("{\"class\":\"TPV\",\"mode\":2,\"lat\":47.62,\"lon\":-122.35}\n")
+        .unwrap();
+    // positions the sun can't be followed at, or which are nonsense, are skipped
+    reports
+        .send("{\"class\":\"TPV\",\"mode\":2,\"lat\":80.0,\"lon\":15.0}\n")
+        .unwrap();
+    reports
+        .send("{\"class\":\"TPV\",\"mode\":2,\"lat\":95.0,\"lon\":15.0}\n")
+        .unwrap();
+    reports
+        .send("{\"class\":\"TPV\",\"mode\":3,\"lat\":45.5152,\"lon\":-122.6784,\"altMSL\":15.0}\n")
+        .unwrap();
+    let location = changed(&mut rx).await;
+    assert_eq!(
+        Location {
+            latitude: 45.5152,
+            longitude: -122.6784,
+            altitude: 15.0,
+        },
+        location
+    );
+}
+
+#[test]
+fn test_fix_expiry() {
+    let configured = Location::default();
+    let opts = GpsdOpts {
+        address: String::new(),
+        min_distance_km: 10.0,
+        max_fix_age_mins: 0,
+    };
+    let (tx, rx) = watch::channel(configured);
+    let mut source = GpsdSource::new(opts, configured);
+
+    // nothing to fall back from without a fix
+    source.expire(&tx);
+    assert!(!rx.has_changed().unwrap());
+
+    source.fixed(
+        Fix {
+            latitude: 10.0,
+            longitude: 10.0,
+            altitude: None,
+        },
+        &tx,
+    );
+    assert_eq!(10.0, rx.borrow().latitude);
+    std::thread::sleep(Duration::from_millis(1));
+    source.expire(&tx);
+    assert_eq!(configured, *rx.borrow());
+}
+
+#[test]
+fn test_parse_report() {
+    assert_eq!(
+        Report::Fix(Fix {
+            latitude: 40.7,
+            longitude: -74.0,
+            altitude: None,
+        }),
+        parse_report("{\"class\":\"TPV\",\"mode\":2,\"lat\":40.7,\"lon\":-74.0,\"alt\":10}")
+            .unwrap()
+    );
+    assert_eq!(
+        Report::NoFix,
+        parse_report("{\"class\":\"TPV\",\"device\":\"/dev/ttyUSB0\",\"mode\":0}").unwrap()
+    );
+    assert_eq!(
+        Report::Other,
+        parse_report("{\"class\":\"SKY\",\"satellites\":[]}").unwrap()
+    );
+    assert!(parse_report("garbage").is_err());
+    assert!(parse_report("{\"class\":\"TPV\",\"mode\":2,\"lat\":40.7,\"lon\":-200}").is_err());
+}
@@ -0,0 +1,106 @@
+mod coords;
+mod gazetteer;
+mod geohash;
+mod gpsd;
+mod plus_code;
+mod timezone;
+
+pub use coords::{parse_latitude, parse_location, parse_longitude};
+pub use gazetteer::{locate_city, search as search_cities, City};
+pub use gpsd::{parse_report, Fix, Gpsd, GpsdSource, Report};
+pub use timezone::{system_timezone, timezone_coordinates};
+
+use serde::Serialize;
+
+use crate::types::*;
+
+/// A position on earth which sunrise and sunset are calculated for. Latitude and
+/// longitude are in degrees, positive to the north and east, and altitude is in meters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Location {
+    pub latitude: f64,
+    pub longitude: f64,
+    pub altitude: f64,
+}
+
+impl Location {
+    /// The great-circle distance to another location, ignoring altitude.
+    pub fn distance_km(&self, other: &Location) -> f64 {
+        const EARTH_RADIUS_KM: f64 = 6371.0;
+
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    /// Checks the sun rises and sets on every day of the year, which brightness is
+    /// scheduled by. Near the poles it stays up or down for days at a time.
+    pub fn check_sun(&self) -> Result<()> {
+        let start = chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap_or_default();
+        let polar = start.iter_days().take(366).find(|date| {
+            sun_times::sun_times(*date, self.latitude, self.longitude, self.altitude).is_none()
+        });
+        match polar {
+            Some(date) => Err(format_err!(
+                "the sun doesn't rise or set on some days at {}, like {}; dlux follows sunrise and sunset, so it can't be used this close to the poles",
+                self,
+                date.format("%B %-d")
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.4}, {:.4}", self.latitude, self.longitude)
+    }
+}
+
+pub fn check_latitude(latitude: f64) -> Result<f64> {
+    if (-90.0..=90.0).contains(&latitude) {
+        Ok(latitude)
+    } else {
+        Err(format_err!(
+            "latitude {} is out of range: -90 <= latitude <= 90",
+            latitude
+        ))
+    }
+}
+
+pub fn check_longitude(longitude: f64) -> Result<f64> {
+    if (-180.0..=180.0).contains(&longitude) {
+        Ok(longitude)
+    } else {
+        Err(format_err!(
+            "longitude {} is out of range: -180 <= longitude <= 180",
+            longitude
+        ))
+    }
+}
+
+#[test]
+fn test_distance() {
+    let seattle = Location {
+        latitude: 47.6062,
+        longitude: -122.3321,
+        altitude: 0.0,
+    };
+    let portland = Location {
+        latitude: 45.5152,
+        longitude: -122.6784,
+        ..seattle
+    };
+    assert!((seattle.distance_km(&portland) - 234.0).abs() < 2.0);
+    assert_eq!(0.0, seattle.distance_km(&seattle));
+
+    assert!(seattle.check_sun().is_ok());
+    let longyearbyen = Location {
+        latitude: 78.0,
+        longitude: 16.0,
+        ..seattle
+    };
+    assert!(longyearbyen.check_sun().is_err());
+}
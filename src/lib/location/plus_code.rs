@@ -0,0 +1,106 @@
+use crate::types::*;
+
+/// Digits of Open Location Codes, in order of value.
+const ALPHABET: &[u8] = b"23456789CFGHJMPQRVWX";
+const SEPARATOR: char = '+';
+/// Number of digits before the separator in a full code.
+const SEPARATOR_POSITION: usize = 8;
+const PADDING: char = '0';
+/// Digits encoded as latitude and longitude pairs, before the grid refinement.
+const PAIR_DIGITS: usize = 10;
+
+/// Decodes a full plus code, like "87G7PX7V+4H", to the center of its area. Short
+/// codes, which are relative to a nearby town, can't be decoded without it.
+pub fn decode(code: &str) -> Result<(f64, f64)> {
+    let invalid = |why: &str| format_err!("{:?} isn't a valid plus code: {}", code, why);
+
+    let code = code.trim().to_ascii_uppercase();
+    match code.find(SEPARATOR) {
+        Some(SEPARATOR_POSITION) => (),
+        Some(i) if i < SEPARATOR_POSITION => {
+            return Err(invalid(
+                "it's a short code, use the full code including the area instead",
+            ))
+        }
+        _ => return Err(invalid("the + must follow the eighth digit")),
+    }
+
+    let (head, tail) = code.split_at(SEPARATOR_POSITION);
+    let tail = &tail[1..];
+    let digits = match head.find(PADDING) {
+        Some(i) => {
+            if i % 2 == 1 || head[i..].chars().any(|c| c != PADDING) || !tail.is_empty() {
+                return Err(invalid("padding is misplaced"));
+            }
+            &head[..i]
+        }
+        None if tail.len() == 1 => return Err(invalid("a single digit follows the +")),
+        None => &code[..],
+    };
+
+    let values = digits
+        .chars()
+        .filter(|&c| c != SEPARATOR)
+        .map(|c| ALPHABET.iter().position(|&a| a as char == c))
+        .collect::<Option<Vec<_>>>()
+        .ok_or_else(|| invalid("it has characters which aren't plus code digits"))?;
+    if values.is_empty() || values[0] > 8 || values.get(1).is_some_and(|&v| v > 17) {
+        return Err(invalid(
+            "it's outside the range of latitudes and longitudes",
+        ));
+    }
+
+    let (mut lat, mut lon) = (-90.0, -180.0);
+    let mut resolution = 20.0 * 20.0;
+    let (mut lat_size, mut lon_size) = (0.0, 0.0);
+    for pair in values[..values.len().min(PAIR_DIGITS)].chunks(2) {
+        resolution /= 20.0;
+        lat += pair[0] as f64 * resolution;
+        lon += pair.get(1).copied().unwrap_or(0) as f64 * resolution;
+        lat_size = resolution;
+        lon_size = resolution;
+    }
+    for &v in values.iter().skip(PAIR_DIGITS) {
+        lat_size /= 5.0;
+        lon_size /= 4.0;
+        lat += (v / 4) as f64 * lat_size;
+        lon += (v % 4) as f64 * lon_size;
+    }
+
+    Ok(((lat + lat_size / 2.0).min(90.0), lon + lon_size / 2.0))
+}
+
+#[test]
+fn test_decode() {
+    let close = |expected: f64, actual: f64| (expected - actual).abs() < 1e-4;
+
+    let (lat, lon) = decode("8FVC9G8F+6X").unwrap();
+    assert!(
+        close(47.365562, lat) && close(8.524937, lon),
+        "{} {}",
+        lat,
+        lon
+    );
+    let (lat, lon) = decode("87g7px7v+4h").unwrap();
+    assert!(
+        close(40.71281, lat) && close(-74.00606, lon),
+        "{} {}",
+        lat,
+        lon
+    );
+    let (lat, lon) = decode("8FVC0000+").unwrap();
+    assert!(close(47.5, lat) && close(8.5, lon), "{} {}", lat, lon);
+    let (lat, lon) = decode("8FVC9G8F+6XQQ").unwrap();
+    assert!(
+        close(47.36566, lat) && close(8.52503, lon),
+        "{} {}",
+        lat,
+        lon
+    );
+
+    assert!(decode("9G8F+6X").is_err());
+    assert!(decode("8FVC0000+6X").is_err());
+    assert!(decode("8FVC9G8F+6").is_err());
+    assert!(decode("XFVC9G8F+6X").is_err());
+    assert!(decode("8FVC9G8I+6X").is_err());
+}
@@ -0,0 +1,137 @@
+use std::path::{Path, PathBuf};
+
+use crate::types::*;
+
+/// Where tzdata is installed unless $TZDIR is set.
+const ZONEINFO: &str = "/usr/share/zoneinfo";
+
+/// Tables of timezones and the coordinates of their principal cities, in order of
+/// preference. zone.tab lists some zones which zone1970.tab merged into others.
+const ZONE_TABLES: &[&str] = &["zone1970.tab", "zone.tab"];
+
+fn zoneinfo_dir() -> PathBuf {
+    std::env::var_os("TZDIR")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
+        .unwrap_or_else(|| ZONEINFO.into())
+}
+
+/// The IANA name of the system's timezone, like America/New_York. It's taken from
+/// $TZ, then the file /etc/localtime links to, then /etc/timezone.
+pub fn system_timezone() -> Result<String> {
+    if let Some(tz) = std::env::var("TZ").ok().filter(|v| !v.is_empty()) {
+        let tz = tz.trim_start_matches(':');
+        return Ok(zone_name(Path::new(tz)).unwrap_or(tz).to_owned());
+    }
+
+    if let Ok(target) = std::fs::read_link("/etc/localtime") {
+        if let Some(name) = zone_name(&target) {
+            return Ok(name.to_owned());
+        }
+    }
+
+    match std::fs::read_to_string("/etc/timezone") {
+        Ok(name) if !name.trim().is_empty() => Ok(name.trim().to_owned()),
+        _ => Err(format_err!(
+            "the system timezone is unknown: set $TZ, link /etc/localtime into {}, or set /etc/timezone",
+            ZONEINFO
+        )),
+    }
+}
+
+/// The zone name of a path within a tzdata directory.
+fn zone_name(path: &Path) -> Option<&str> {
+    let path = path.to_str()?;
+    path.rfind("zoneinfo/")
+        .map(|i| &path[i + "zoneinfo/".len()..])
+}
+
+/// Approximate coordinates of the timezone: those of its principal city, as listed
+/// by tzdata. Links between zones, like US/Eastern to America/New_York, are followed
+/// when tzdata installs them as symlinks.
+pub fn timezone_coordinates(name: &str) -> Result<(f64, f64)> {
+    let dir = zoneinfo_dir();
+    let canonical = std::fs::canonicalize(dir.join(name))
+        .ok()
+        .and_then(|p| zone_name(&p).map(str::to_owned));
+    let names = std::iter::once(name).chain(canonical.as_deref());
+
+    let tables = ZONE_TABLES
+        .iter()
+        .filter_map(|t| std::fs::read_to_string(dir.join(t)).ok())
+        .collect::<Vec<_>>();
+    if tables.is_empty() {
+        return Err(format_err!(
+            "no timezone tables were found in {:?}; is tzdata installed?",
+            dir
+        ));
+    }
+
+    names
+        .flat_map(|n| tables.iter().map(move |t| (n, t)))
+        .find_map(|(n, t)| find_zone(t, n))
+        .ok_or_else(|| format_err!("timezone {} has no known location", name))
+}
+
+/// Finds the coordinates of the zone in a zone.tab-like table.
+fn find_zone(table: &str, name: &str) -> Option<(f64, f64)> {
+    table
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split('\t').collect::<Vec<_>>())
+        .find(|cols| cols.get(2) == Some(&name))
+        .and_then(|cols| parse_iso6709(cols[1]))
+}
+
+/// Parses ISO 6709 coordinates in the form tzdata uses, ±DDMM±DDDMM or
+/// ±DDMMSS±DDDMMSS.
+fn parse_iso6709(s: &str) -> Option<(f64, f64)> {
+    let split = s.get(1..)?.find(['+', '-'])? + 1;
+    let (lat, lon) = s.split_at(split);
+    Some((parse_dms(lat, 2)?, parse_dms(lon, 3)?))
+}
+
+/// Parses a signed angle of degrees with the number of digits, then minutes and
+/// optionally seconds.
+fn parse_dms(s: &str, degree_digits: usize) -> Option<f64> {
+    let sign = match s.get(..1)? {
+        "+" => 1.0,
+        "-" => -1.0,
+        _ => return None,
+    };
+    let digits = &s[1..];
+    if !digits.bytes().all(|b| b.is_ascii_digit())
+        || (digits.len() != degree_digits + 2 && digits.len() != degree_digits + 4)
+    {
+        return None;
+    }
+
+    let part = |range: std::ops::Range<usize>| digits.get(range)?.parse::<f64>().ok();
+    let degrees = part(0..degree_digits)?;
+    let minutes = part(degree_digits..degree_digits + 2)?;
+    let seconds = part(degree_digits + 2..digits.len()).unwrap_or(0.0);
+    Some(sign * (degrees + minutes / 60.0 + seconds / 3600.0))
+}
+
+#[test]
+fn test_find_zone() {
+    let table = "# comment\n\
+                 AD\t+4230+00131\tEurope/Andorra\n\
+                 US\t+404251-0740023\tAmerica/New_York\tEastern (most areas)\n\
+                 AR\t-3436-05827\tAmerica/Argentina/Buenos_Aires\tBuenos Aires (BA, CF)\n";
+
+    let (lat, lon) = find_zone(table, "America/New_York").unwrap();
+    assert!((lat - 40.7142).abs() < 1e-3, "{}", lat);
+    assert!((lon + 74.0064).abs() < 1e-3, "{}", lon);
+
+    let (lat, lon) = find_zone(table, "America/Argentina/Buenos_Aires").unwrap();
+    assert!((lat + 34.6).abs() < 1e-3, "{}", lat);
+    assert!((lon + 58.45).abs() < 1e-3, "{}", lon);
+
+    assert_eq!(None, find_zone(table, "Etc/UTC"));
+    assert_eq!(None, parse_iso6709("+4230+131"));
+    assert_eq!(
+        Some("America/New_York"),
+        zone_name(Path::new("/usr/share/zoneinfo/America/New_York"))
+    );
+}
@@ -2,16 +2,37 @@ pub use log::{debug, error, info, trace, warn};
 use serde::Deserialize;
 use structopt::StructOpt;
 
-use clap::arg_enum;
+#[derive(Debug, Default, Deserialize, Eq, PartialEq, Clone, Copy, PartialOrd, Ord)]
+pub enum LevelFilter {
+    Trace,
+    Debug,
+    #[default]
+    Info,
+    Warn,
+    Error,
+}
+
+impl std::str::FromStr for LevelFilter {
+    type Err = String;
 
-arg_enum! {
-    #[derive(Debug, Deserialize, Eq, PartialEq, Clone, Copy, PartialOrd, Ord)]
-    pub enum LevelFilter {
-        Trace,
-        Debug,
-        Info,
-        Warn,
-        Error,
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "trace" => Ok(Self::Trace),
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warn" => Ok(Self::Warn),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "valid values: trace, debug, info, warn, error; got {:?}",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for LevelFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
     }
 }
 
@@ -27,18 +48,30 @@ impl From<LevelFilter> for log::LevelFilter {
     }
 }
 
-impl Default for LevelFilter {
-    fn default() -> Self {
-        LevelFilter::Info
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy, Deserialize, PartialOrd, Ord)]
+pub enum WriteStyle {
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for WriteStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("valid values: auto, always, never; got {:?}", s)),
+        }
     }
 }
 
-arg_enum! {
-    #[derive(Eq, PartialEq, Debug, Clone, Copy, Deserialize, PartialOrd, Ord)]
-    pub enum WriteStyle {
-        Auto,
-        Always,
-        Never,
+impl std::fmt::Display for WriteStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Debug::fmt(self, f)
     }
 }
 
@@ -52,23 +85,17 @@ impl From<WriteStyle> for env_logger::WriteStyle {
     }
 }
 
-impl Default for WriteStyle {
-    fn default() -> Self {
-        WriteStyle::Auto
-    }
-}
-
-#[derive(StructOpt, Deserialize, Debug)]
+#[derive(StructOpt, Deserialize, Debug, Default)]
 pub struct LogOpts {
     /// minimum log level printed to STDERR. Choose from:
     /// trace, debug, info, warn, error, off.
-    #[structopt(long = "log-level", default_value = "info")]
+    #[structopt(long = "log-level", env = "DLUX_LOGGING_LEVEL", default_value = "info")]
     #[serde(default)]
     pub level: LevelFilter,
 
     /// controls when log output is colored. Choose from: auto,
     /// always, and never. RUST_LOG env var may override this parameter.
-    #[structopt(long = "log-style", default_value)]
+    #[structopt(long = "log-style", env = "DLUX_LOGGING_STYLE", default_value)]
     #[serde(default)]
     pub style: WriteStyle,
 }
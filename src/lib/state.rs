@@ -0,0 +1,241 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{logging::*, types::*};
+
+/// Whether the sun is up, which decides the brightness displays are set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Day,
+    Night,
+}
+
+impl Phase {
+    pub fn from_daytime(is_daytime: bool) -> Self {
+        if is_daytime {
+            Self::Day
+        } else {
+            Self::Night
+        }
+    }
+
+    pub fn is_daytime(self) -> bool {
+        self == Self::Day
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Day => write!(f, "daytime"),
+            Self::Night => write!(f, "nighttime"),
+        }
+    }
+}
+
+/// A brightness percentage which replaces a display's configured target until the
+/// phase it was set in ends.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct BrightnessOverride {
+    pub brightness: f64,
+    pub phase: Phase,
+    /// when the override was made. Overrides saved without one have ended.
+    #[serde(default)]
+    pub since: DateTime<Utc>,
+}
+
+impl BrightnessOverride {
+    /// Whether the override was made during the phase, which started at the time,
+    /// rather than an earlier one.
+    pub fn is_active(&self, phase: Phase, started: DateTime<Utc>) -> bool {
+        self.phase == phase && self.since >= started
+    }
+}
+
+/// What the daemon last knew about a display. Brightnesses are percentages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DisplayState {
+    /// brightness last applied to the display.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<f64>,
+    /// brightness last read back from the display.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub observed: Option<f64>,
+    /// phase the target was applied for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    /// brightness set with the display's buttons, kept until the phase ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness_override: Option<BrightnessOverride>,
+    /// added to the configured brightness after it was shifted with the display's
+    /// buttons.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<f64>,
+    /// brightness before the daemon first changed it, kept to restore on exit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub original: Option<f64>,
+}
+
+impl DisplayState {
+    /// The override for the phase which started at the time, if one is active.
+    pub fn override_for(&self, phase: Phase, started: DateTime<Utc>) -> Option<f64> {
+        self.brightness_override
+            .filter(|o| o.is_active(phase, started))
+            .map(|o| o.brightness)
+    }
+
+    /// The brightness the display should have for the phase which started at the time
+    /// given its configured brightness, after overrides and offsets.
+    pub fn target_for(&self, configured: f64, phase: Phase, started: DateTime<Utc>) -> f64 {
+        self.override_for(phase, started)
+            .unwrap_or_else(|| (configured + self.offset.unwrap_or(0.0)).clamp(0.0, 100.0))
+    }
+
+    /// Whether the display was last set to target for the phase.
+    pub fn applied(&self, target: f64, phase: Phase) -> bool {
+        self.phase == Some(phase)
+            && self
+                .target
+                .map(|t| (t - target).abs() < 0.5)
+                .unwrap_or(false)
+            && self
+                .observed
+                .map(|o| (o - target).abs() < 0.5)
+                .unwrap_or(false)
+    }
+}
+
+/// State persists what the daemon knows about each display across restarts. Displays
+/// are keyed by their identity.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct State {
+    #[serde(skip)]
+    path: PathBuf,
+    /// profile the daemon was last switched to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// phase hooks were last run for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    #[serde(default)]
+    pub displays: BTreeMap<String, DisplayState>,
+}
+
+impl State {
+    /// The default state file: in $STATE_DIRECTORY when run by systemd, otherwise
+    /// $XDG_STATE_HOME/dlux or ~/.local/state/dlux.
+    pub fn default_path() -> Option<PathBuf> {
+        let var = |name| std::env::var_os(name).filter(|v| !v.is_empty());
+
+        let dir = match var("STATE_DIRECTORY") {
+            // systemd separates multiple directories with colons.
+            Some(dirs) => PathBuf::from(dirs.to_string_lossy().split(':').next()?),
+            None => var("XDG_STATE_HOME")
+                .map(PathBuf::from)
+                .or_else(|| var("HOME").map(|h| PathBuf::from(h).join(".local/state")))?
+                .join("dlux"),
+        };
+        Some(dir.join("state.yaml"))
+    }
+
+    /// Loads state from the path. A missing file is empty state.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut state: State = match std::fs::read_to_string(path) {
+            Ok(s) => serde_yaml::from_str(&s)
+                .with_context(|| format!("failed to parse state file {:?}", path))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("no state file at {:?}, starting fresh", path);
+                State::default()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        state.path = path.to_owned();
+        Ok(state)
+    }
+
+    /// Like load, but unreadable state is discarded rather than stopping the daemon.
+    pub fn load_or_default(path: &Path) -> Self {
+        Self::load(path).unwrap_or_else(|e| {
+            warn!("ignoring daemon state: {:#}", e);
+            State {
+                path: path.to_owned(),
+                ..State::default()
+            }
+        })
+    }
+
+    /// Writes state to where it was loaded from. The file is replaced atomically so
+    /// it's never left partially written.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let tmp = self.path.with_extension("yaml.tmp");
+        std::fs::write(&tmp, serde_yaml::to_string(self)?)?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("failed to write state file {:?}", self.path))?;
+        Ok(())
+    }
+
+    pub fn get(&self, identity: &str) -> Option<&DisplayState> {
+        self.displays.get(identity)
+    }
+
+    pub fn display(&mut self, identity: &str) -> &mut DisplayState {
+        self.displays.entry(identity.to_owned()).or_default()
+    }
+}
+
+#[test]
+fn test_state_roundtrip() {
+    let path = std::env::temp_dir()
+        .join(format!("dlux-state-{}", std::process::id()))
+        .join("state.yaml");
+
+    let mut state = State::load(&path).unwrap();
+    assert!(state.displays.is_empty());
+    let sunset: DateTime<Utc> = "2024-01-01T17:00:00Z".parse().unwrap();
+
+    *state.display("DEL DELL U2415 41191 (SN: ABC, 1)") = DisplayState {
+        target: Some(80.0),
+        observed: Some(80.0),
+        phase: Some(Phase::Day),
+        brightness_override: Some(BrightnessOverride {
+            brightness: 30.0,
+            phase: Phase::Night,
+            since: sunset + chrono::Duration::hours(1),
+        }),
+        offset: Some(-10.0),
+        original: None,
+    };
+    state.phase = Some(Phase::Night);
+    state.save().unwrap();
+
+    let loaded = State::load(&path).unwrap();
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    assert_eq!(Some(Phase::Night), loaded.phase);
+
+    let disp = loaded.get("DEL DELL U2415 41191 (SN: ABC, 1)").unwrap();
+    assert!(disp.applied(80.0, Phase::Day));
+    assert!(!disp.applied(80.0, Phase::Night));
+    assert!(!disp.applied(60.0, Phase::Day));
+    assert_eq!(None, disp.override_for(Phase::Day, sunset));
+    assert_eq!(Some(30.0), disp.override_for(Phase::Night, sunset));
+    assert_eq!(30.0, disp.target_for(60.0, Phase::Night, sunset));
+    assert_eq!(50.0, disp.target_for(60.0, Phase::Day, sunset));
+    assert_eq!(0.0, disp.target_for(5.0, Phase::Day, sunset));
+    // made the night before
+    let next_sunset = sunset + chrono::Duration::days(1);
+    assert_eq!(None, disp.override_for(Phase::Night, next_sunset));
+    assert_eq!(50.0, disp.target_for(60.0, Phase::Night, next_sunset));
+
+    // overrides saved before they were dated have ended
+    let legacy: BrightnessOverride =
+        serde_yaml::from_str("{brightness: 30, phase: night}").unwrap();
+    assert!(!legacy.is_active(Phase::Night, sunset));
+}
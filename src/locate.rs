@@ -0,0 +1,26 @@
+use structopt::StructOpt;
+
+use lib::{location::search_cities, types::*};
+
+#[derive(StructOpt, Debug)]
+pub struct Opts {
+    /// city name or the start of one, optionally followed by a region or country like
+    /// "Portland, OR" or "Paris, FR".
+    pub query: Vec<String>,
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let query = opts.query.join(" ");
+    let cities = search_cities(&query);
+    if cities.is_empty() {
+        return Err(format_err!("no city matches {:?}", query));
+    }
+
+    for city in cities {
+        println!(
+            "{}: latitude {:.4}, longitude {:.4}",
+            city, city.latitude, city.longitude
+        );
+    }
+    Ok(())
+}
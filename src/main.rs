@@ -1,5 +1,8 @@
+mod check;
 mod daemon;
+mod locate;
 mod probe;
+mod profile;
 
 use std::convert::TryInto;
 use structopt::StructOpt;
@@ -11,20 +14,42 @@ use lib::types::*;
     name = "dlux",
     about = "Dynamic hardware monitor brightness adjustment"
 )]
+// Parsed once at startup, so the size of the config options doesn't matter.
+#[allow(clippy::large_enum_variant)]
 enum Command {
     Daemon(daemon::Opts),
     Start(lib::config::Opts),
     Probe(probe::Opts),
+    /// checks a daemon config file and prints the rules it results in.
+    CheckConfig(check::Opts),
+    /// shows the daemon's brightness profiles, or switches to another.
+    Profile(profile::Opts),
+    /// lists the built-in cities matching a name, for use as geo.city.
+    Locate(locate::Opts),
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
-    let opts: lib::config::Config = match Command::from_args() {
-        Command::Daemon(opts) => opts.config.try_into(),
-        Command::Start(opts) => opts.try_into(),
+    let (opts, profile): (lib::config::Config, _) = match Command::from_args() {
+        Command::Daemon(opts) => {
+            let (format, profile) = (opts.format, opts.profile);
+            match opts.config {
+                Some(path) => Ok(path),
+                None => lib::config::Opts::find(),
+            }
+            .and_then(|path| lib::config::Config::from_file(&path, format))
+            .map(|cfg| (cfg, profile))
+        }
+        Command::Start(opts) => opts
+            .with_env()
+            .and_then(TryInto::try_into)
+            .map(|cfg| (cfg, None)),
         Command::Probe(opts) => return probe::run(opts).await,
+        Command::CheckConfig(opts) => return check::run(opts).await,
+        Command::Profile(opts) => return profile::run(opts).await,
+        Command::Locate(opts) => return locate::run(opts).await,
     }?;
 
     lib::logging::init_logger(&opts.logging);
-    daemon::run(opts).await
+    daemon::run(opts, profile).await
 }
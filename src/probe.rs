@@ -4,12 +4,21 @@ use regex::{escape, Regex};
 use serde_yaml::to_string;
 use structopt::StructOpt;
 
-use lib::{config, logging::*, prelude::*, types::*};
+use lib::{config, display::shared_identities, logging::*, prelude::*, types::*};
 
 #[derive(StructOpt, Debug)]
 pub struct Opts {
     #[structopt(flatten)]
     pub logging: LogOpts,
+
+    /// optional daemon config file whose EDID overrides are applied to
+    /// discovered devices. Explains which of its rules match each device.
+    #[structopt(long, short)]
+    pub config: Option<std::path::PathBuf>,
+
+    /// config file format: yaml, toml, or json. Detected from the extension if unset.
+    #[structopt(long)]
+    pub format: Option<config::Format>,
 }
 
 pub async fn run(mut opts: Opts) -> Result<()> {
@@ -18,21 +27,61 @@ pub async fn run(mut opts: Opts) -> Result<()> {
     }
     init_logger(&opts.logging);
 
+    let cfg = opts
+        .config
+        .as_ref()
+        .map(|path| config::Config::from_file(path, opts.format))
+        .transpose()?;
+    let discovery = cfg
+        .as_ref()
+        .map(|c| c.discovery.clone())
+        .unwrap_or_default();
+
     let def = config::DeviceConfig::default();
-    let mut disps = Displays::new(vec![&def])?;
+    let mut disps = Displays::new(vec![&def], &discovery)?;
 
-    let disps = disps
+    let infos = disps
         .iter_mut()
-        .map(|d| {
+        .filter_map(|d| {
+            match d.capabilities() {
+                Ok(caps) => info!("{} capabilities: {}", d, caps),
+                Err(e) => info!("{}", e),
+            }
+
             debug!("parsing edid for {}", d);
-            let edid = d.display_info()?;
+            match d.display_info() {
+                Ok(edid) => Some(edid),
+                Err(e) => {
+                    warn!("skipping {}: {}", d, e);
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+    let shared = shared_identities(&infos);
+
+    let disps = infos
+        .iter()
+        .map(|edid| {
             debug!("edid: {}", edid);
+            trace!("parsed edid: {:?}", edid);
+            if let Some(nits) = edid.max_luminance {
+                info!("{} advertises a maximum luminance of {} nits", edid, nits);
+            }
+            if let Some(cfg) = &cfg {
+                explain_rules(cfg, edid);
+            }
 
-            Ok(config::DeviceOpts::new(
+            let mut opts = config::DeviceOpts::new(
                 Some(Regex::new(&escape(&edid.model))?),
                 Some(Regex::new(&escape(&edid.manufacturer))?),
-                Some(edid.serial),
-            ))
+                Some(edid.serial.clone()).filter(|s| !s.is_empty()),
+            );
+            if let Some(group) = shared.iter().find(|g| g.iter().any(|i| i.bus == edid.bus)) {
+                distinguish(&mut opts.matcher, edid, group);
+            }
+
+            Ok(opts)
         })
         .collect::<Result<Vec<_>>>()?;
 
@@ -46,3 +95,57 @@ pub async fn run(mut opts: Opts) -> Result<()> {
 
     Ok(())
 }
+
+/// explain_rules logs which rule of the config's starting profile claims the device
+/// and why earlier rules do not.
+fn explain_rules(cfg: &config::Config, info: &DeviceInfo) {
+    let rules = cfg.rules(&cfg.profile).unwrap_or_default();
+    for (i, rule) in rules.iter().enumerate() {
+        match rule.matcher.explain(info) {
+            Ok(why) => {
+                info!(
+                    "{} on {}: rule {} matches: {}",
+                    info,
+                    info.bus,
+                    i + 1,
+                    why.join(", ")
+                );
+                return;
+            }
+            Err(why) => info!(
+                "{} on {}: rule {} does not match: {}",
+                info,
+                info.bus,
+                i + 1,
+                why.join(", ")
+            ),
+        }
+    }
+
+    info!("{} on {}: no rule matches", info, info.bus);
+}
+
+/// distinguish adds a field to the matcher which tells the display apart from others
+/// with the same identity. Connectors are preferred as they're stable across reboots,
+/// followed by the EDID hash if it's unique, then the bus.
+fn distinguish(matcher: &mut DeviceMatcher, info: &DeviceInfo, group: &[&DeviceInfo]) {
+    let hash_unique = group
+        .iter()
+        .filter(|i| i.edid_hash == info.edid_hash)
+        .count()
+        == 1;
+
+    match &info.connector {
+        Some(conn) => matcher.connector = Some(conn.clone()),
+        None if hash_unique && !info.edid_hash.is_empty() => {
+            matcher.edid_hash = Some(info.edid_hash.clone())
+        }
+        None => {
+            warn!(
+                "{} can only be told apart from identical displays by its bus, which may change across reboots",
+                info.bus
+            );
+            matcher.bus = Some(info.bus.clone());
+        }
+    }
+}
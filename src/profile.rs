@@ -0,0 +1,62 @@
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use lib::{
+    config::{Format, Opts as ConfigOpts},
+    control::{self, Request},
+    logging::*,
+    types::*,
+};
+
+#[derive(StructOpt, Debug)]
+pub struct Opts {
+    /// the daemon's control socket. Defaults to the config's control_socket, or
+    /// control.sock in $RUNTIME_DIRECTORY, $XDG_RUNTIME_DIR/dlux, or /run/dlux.
+    #[structopt(long)]
+    pub socket: Option<PathBuf>,
+
+    /// config file the daemon was started with, for its control_socket. If absent, the
+    /// daemon's default config is used when there is one.
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+
+    /// config file format: yaml, toml, or json. Detected from the extension if unset.
+    #[structopt(long)]
+    pub format: Option<Format>,
+
+    /// profile to switch to. Without one, the profiles are listed with the active one
+    /// marked.
+    pub name: Option<String>,
+}
+
+pub async fn run(opts: Opts) -> Result<()> {
+    let path = match opts.socket {
+        Some(path) => path,
+        None => configured_socket(opts.config, opts.format)?
+            .unwrap_or_else(control::default_socket_path),
+    };
+    let req = match opts.name {
+        Some(name) => Request::SetProfile(name),
+        None => Request::Profiles,
+    };
+
+    print!("{}", control::send(&path, &req).await?);
+    Ok(())
+}
+
+/// The control_socket set in the config file, or the default one if it exists.
+fn configured_socket(config: Option<PathBuf>, format: Option<Format>) -> Result<Option<PathBuf>> {
+    let path = match config {
+        Some(path) => path,
+        None => match ConfigOpts::find() {
+            Ok(path) => path,
+            Err(e) => {
+                debug!("using the default control socket: {}", e);
+                return Ok(None);
+            }
+        },
+    };
+
+    Ok(ConfigOpts::from_file(&path, format)?.control_socket)
+}